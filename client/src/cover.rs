@@ -0,0 +1,82 @@
+// Cover traffic: requests leave only in slots at randomized intervals, and a
+// slot with no request queued sends a dummy one instead.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use msg::EncryptedActionRequest;
+use rand::Rng;
+
+// Dummy requests whose answers are still awaited, older ones are forgotten.
+const MAX_DUMMIES: usize = 16;
+
+#[derive(Debug)]
+pub struct Cover {
+    period: Duration,
+    next_slot: Instant,
+    queue: VecDeque<EncryptedActionRequest>,
+    dummies: VecDeque<EncryptedActionRequest>,
+}
+
+impl Cover {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            next_slot: Instant::now(),
+            queue: VecDeque::new(),
+            dummies: VecDeque::new(),
+        }
+    }
+
+    pub fn queue(&mut self, request: EncryptedActionRequest) {
+        if !self.queue.contains(&request) {
+            self.queue.push_back(request);
+        }
+    }
+
+    // Whether a slot is due, taking it if so.
+    pub fn take_slot<R: Rng>(&mut self, rng: &mut R) -> bool {
+        if Instant::now() < self.next_slot {
+            return false;
+        }
+        self.next_slot = Instant::now() + msg::cover_slot_interval(rng, self.period);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<EncryptedActionRequest> {
+        self.queue.pop_front()
+    }
+
+    pub fn is_queued(&self, request: &EncryptedActionRequest) -> bool {
+        self.queue.contains(request)
+    }
+
+    // Drops the request unless it was sent already.
+    pub fn cancel(&mut self, request: &EncryptedActionRequest) {
+        self.queue.retain(|other| other != request);
+    }
+
+    pub fn until_next_slot(&self) -> Duration {
+        self.next_slot.saturating_duration_since(Instant::now())
+    }
+
+    pub fn add_dummy(&mut self, request: EncryptedActionRequest) {
+        self.dummies.push_back(request);
+        if self.dummies.len() > MAX_DUMMIES {
+            self.dummies.pop_front();
+        }
+    }
+
+    pub fn has_dummies(&self) -> bool {
+        !self.dummies.is_empty()
+    }
+
+    // Forgets the dummy request, returning whether it was one.
+    pub fn remove_dummy(&mut self, request: &EncryptedActionRequest) -> bool {
+        let len = self.dummies.len();
+        self.dummies.retain(|other| other != request);
+        self.dummies.len() < len
+    }
+}
@@ -1,59 +1,378 @@
+mod cover;
+mod merge;
+mod tree;
+mod worker;
+
 use std::{
-    fs,
-    time::{Duration, Instant},
+    collections::{HashSet, VecDeque},
+    fs, mem,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant, SystemTime},
 };
 
+use cover::Cover;
 use eframe::{
     egui::{
-        Button, CentralPanel, FontData, FontDefinitions, FontTweak, Label, TextBuffer, TextEdit,
-        TextStyle, Ui,
+        Button, CentralPanel, CollapsingHeader, Color32, ComboBox, DragValue, FontData,
+        FontDefinitions, FontTweak, Key, Label, RichText, ScrollArea, SelectableLabel, SidePanel,
+        Spinner, TextBuffer, TextEdit, TextStyle, TopBottomPanel, Ui, Window,
     },
     epaint::{FontFamily, Vec2},
 };
+use either::Either;
+use merge::{Choice, Hunk};
 use msg::{
-    decrypt_aes_key, ActionRequest, AesKey, EncryptedActionRequest, GreetRequest, Msg, Paste,
-    RsaPrivateKey,
+    decrypt_aes_key, ActionRequest, ActionResponse, AesKey, Attachment, AttachmentChunk,
+    AttachmentInfo, EncryptedActionRequest, Expiry, Grant, GreetRequest, ListOptions, Msg, Paste,
+    PasteInfo, Permission, RejectReason, RevisionInfo, RsaPrivateKey, RsaPublicKey, SearchMatch,
+    SortBy, MAX_ATTACHMENT_LEN,
 };
-use rand::{rngs::ThreadRng, thread_rng, CryptoRng, RngCore};
+use rand::{rngs::ThreadRng, thread_rng, CryptoRng, Rng, RngCore};
+use tree::Folder;
+use worker::{Command, Event, Worker};
+use zeroize::{Zeroize, Zeroizing};
 
-const RSA_PRIVATE_KEY_FILE_NAME: &'static str = "rsa_private_key.json";
+const RSA_PRIVATE_KEY_FILE_NAME: &str = "rsa_private_key.json";
 
-fn generate_rsa_private_key<R: CryptoRng + RngCore>(rng: &mut R) -> RsaPrivateKey {
-    let key = RsaPrivateKey::new(rng, 1024).unwrap();
+fn generate_rsa_private_key<R: CryptoRng + RngCore>(rng: &mut R) -> anyhow::Result<RsaPrivateKey> {
+    let key = RsaPrivateKey::new(rng, 1024)?;
     fs::write(
         RSA_PRIVATE_KEY_FILE_NAME,
-        serde_json::to_vec_pretty(&key).unwrap(),
-    )
-    .unwrap();
-    key
+        Zeroizing::new(serde_json::to_vec_pretty(&key)?),
+    )?;
+    Ok(key)
 }
 
-fn rsa_private_key<R: CryptoRng + RngCore>(rng: &mut R) -> RsaPrivateKey {
+fn load_rsa_private_key<R: CryptoRng + RngCore>(rng: &mut R) -> anyhow::Result<RsaPrivateKey> {
     let read = fs::read(RSA_PRIVATE_KEY_FILE_NAME)
         .ok()
-        .map(|bytes| serde_json::from_slice(&bytes).ok())
-        .flatten();
-    read.unwrap_or_else(|| generate_rsa_private_key(rng))
+        .map(Zeroizing::new)
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+    read.map_or_else(|| generate_rsa_private_key(rng), Ok)
+}
+
+// Replaces the text in an editor buffer, overwriting the old text before it is freed.
+fn set_text(buffer: &mut String, text: String) {
+    buffer.zeroize();
+    *buffer = text;
+}
+
+// An error shown in the status bar until dismissed. Failed network commands
+// keep the command around so that the user can retry it.
+struct Failure {
+    message: String,
+    retry: Option<Command>,
+}
+
+enum Answer {
+    Response(ActionResponse),
+    // the request was sent with an outdated session key and has to be repeated
+    Rekeyed,
+}
+
+// A read request whose response the ui is waiting for.
+struct PendingRequest {
+    request: ActionRequest,
+    encrypted_request: EncryptedActionRequest,
+    session_key: AesKey,
+    sent_instant: Instant,
+}
+
+enum WriteStatus {
+    Pending,
+    Done,
+    Failed(String),
+}
+
+// The chunks of an attachment still to be uploaded. Each is sent once the
+// server has stored the one before, so that a large file never floods the mailbox.
+struct Upload {
+    name: String,
+    chunks: VecDeque<AttachmentChunk>,
+}
+
+// An attachment being downloaded chunk by chunk into `bytes`.
+struct Download {
+    attachment: AttachmentInfo,
+    path: PathBuf,
+    bytes: Vec<u8>,
+    // the chunk requested
+    index: u32,
+    pending: PendingRequest,
+}
+
+// A Mut that the server refused because the paste changed in the meantime.
+struct Conflict {
+    local: Paste,
+    base_revision: Option<u64>,
+    current: Paste,
+    revision: u64,
+}
+
+// A three-way merge of a conflicting local edit with the current revision.
+struct Merge {
+    local: Paste,
+    current: Paste,
+    revision: u64,
+    // None while the base revision is being fetched
+    hunks: Option<Vec<Hunk>>,
+    result: Zeroizing<String>,
+}
+
+enum ConflictChoice {
+    Overwrite,
+    Merge,
+    TakeCurrent,
+    Cancel,
+}
+
+fn hunk_lines(ui: &mut Ui, title: &str, lines: &[String], color: Color32) {
+    ui.label(RichText::new(title).small());
+    for line in lines {
+        ui.label(
+            RichText::new(line.trim_end_matches(['\r', '\n']))
+                .color(color)
+                .monospace(),
+        );
+    }
+}
+
+// A New, Mut or Remove request kept until the server acknowledges it, so that
+// failed and unanswered writes stay visible and can be retried.
+struct Write {
+    request: ActionRequest,
+    encrypted_request: EncryptedActionRequest,
+    session_key: AesKey,
+    sent_instant: Instant,
+    status: WriteStatus,
+}
+
+fn describe_request(request: &ActionRequest) -> String {
+    match request {
+        ActionRequest::Get { name } => format!("Получение \"{name}\""),
+        ActionRequest::Remove { name } => format!("Удаление \"{name}\""),
+        ActionRequest::Mut { paste, .. } => format!("Изменение \"{}\"", paste.name),
+        ActionRequest::New { paste, .. } => format!("Создание \"{}\"", paste.name),
+        ActionRequest::List { .. } => "Список записей".into(),
+        ActionRequest::Search { query, .. } => format!("Поиск \"{query}\""),
+        ActionRequest::Rename { from, to } => format!("Переименование \"{from}\" в \"{to}\""),
+        ActionRequest::History { name } => format!("История \"{name}\""),
+        ActionRequest::Revision { name, revision } => format!("Ревизия №{revision} \"{name}\""),
+        ActionRequest::Restore { name, revision } => {
+            format!("Восстановление \"{name}\" до ревизии №{revision}")
+        }
+        ActionRequest::Append { name, .. } => format!("Дополнение \"{name}\""),
+        ActionRequest::Attach { chunk, .. } => format!(
+            "Вложение \"{}\" ({} из {})",
+            chunk.attachment,
+            chunk.index + 1,
+            chunk.count
+        ),
+        ActionRequest::Attachments { name } => format!("Вложения \"{name}\""),
+        ActionRequest::Download { attachment, .. } => format!("Скачивание \"{attachment}\""),
+        ActionRequest::Detach { attachment, .. } => {
+            format!("Удаление вложения \"{attachment}\"")
+        }
+        ActionRequest::MoveFolder { from, to } => {
+            format!("Перемещение папки \"{from}\" в \"{to}\"")
+        }
+        ActionRequest::RemoveFolder { folder } => format!("Удаление папки \"{folder}\""),
+        ActionRequest::Grant { name, .. } => format!("Доступ к \"{name}\""),
+        ActionRequest::Revoke { name, .. } => format!("Отзыв доступа к \"{name}\""),
+        ActionRequest::Grants { name } => format!("Список доступа к \"{name}\""),
+        ActionRequest::Cover => "Фоновый запрос".into(),
+    }
+}
+
+fn format_modified(modified: SystemTime) -> String {
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default()
+        .as_secs();
+    match age {
+        0..=59 => "только что".into(),
+        60..=3599 => format!("{} мин. назад", age / 60),
+        3600..=86399 => format!("{} ч. назад", age / 3600),
+        _ => format!("{} дн. назад", age / 86400),
+    }
+}
+
+fn format_remaining(expires_at: SystemTime) -> String {
+    let left = expires_at
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .as_secs();
+    match left {
+        0..=59 => "меньше минуты".into(),
+        60..=3599 => format!("{} мин.", left / 60),
+        3600..=86399 => format!("{} ч.", left / 3600),
+        _ => format!("{} дн.", left / 86400),
+    }
+}
+
+fn describe_expiry(paste_info: &PasteInfo) -> Option<String> {
+    match (paste_info.expires_at, paste_info.reads_left) {
+        (None, None) => None,
+        (Some(expires_at), None) => {
+            Some(format!("удалится через {}", format_remaining(expires_at)))
+        }
+        (None, Some(reads_left)) => Some(format!("осталось прочтений: {reads_left}")),
+        (Some(expires_at), Some(reads_left)) => Some(format!(
+            "удалится через {} или после прочтений: {reads_left}",
+            format_remaining(expires_at)
+        )),
+    }
+}
+
+fn describe_expires_in(expires_in: Option<Duration>) -> String {
+    match expires_in.map(|expires_in| expires_in.as_secs()) {
+        None => "никогда".into(),
+        Some(secs @ 0..=3599) => format!("{} мин.", secs / 60),
+        Some(secs @ 3600..=86399) => format!("{} ч.", secs / 3600),
+        Some(secs) => format!("{} дн.", secs / 86400),
+    }
+}
+
+// Tags are entered as a comma separated list.
+fn parse_tags(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(Into::into)
+        .collect()
+}
+
+fn describe_paste_info(name: &str, paste_info: &PasteInfo) -> String {
+    let mut text = format!(
+        "{}\n{} байт, изменена {}, создана {}",
+        name,
+        paste_info.len,
+        format_modified(paste_info.modified),
+        format_modified(paste_info.created)
+    );
+    for tag in &paste_info.tags {
+        text.push_str(&format!(" #{tag}"));
+    }
+    match paste_info.shared {
+        Some(Permission::Read) => text.push_str("\nобщая, только чтение"),
+        Some(Permission::Write) => text.push_str("\nобщая"),
+        None => {}
+    }
+    if let Some(expiry) = describe_expiry(paste_info) {
+        text.push('\n');
+        text.push_str(&expiry);
+    }
+    text
+}
+
+// The start of the digits of a serialized RSA key, enough to tell keys apart.
+fn describe_key(json: &str) -> String {
+    format!(
+        "{} ...",
+        json.trim_start_matches(|ch: char| !ch.is_numeric())
+            .char_range(0..30)
+    )
+}
+
+fn describe_sort_by(sort_by: SortBy) -> &'static str {
+    match sort_by {
+        SortBy::Name => "по имени",
+        SortBy::Created => "по созданию",
+        SortBy::Modified => "по изменению",
+        SortBy::Len => "по размеру",
+    }
 }
 
 struct App {
     rng: ThreadRng,
+    worker: Worker,
+    // loaded on first use
+    rsa_private_key: Option<RsaPrivateKey>,
     session_key: Option<AesKey>,
+    greet_request_sent: bool,
     msgs: Vec<(gist::GistId, Msg)>,
+    collecting: bool,
+    // gists whose msg could not be decoded, no longer fetched from then on
+    quarantine: HashSet<gist::GistId>,
+    failures: Vec<Failure>,
     pending_request_retry_instant: Instant,
     pending_get_request: Option<EncryptedActionRequest>,
     pending_get_request_start_instant: Instant,
+    writes: Vec<Write>,
+    pending_list_request: Option<PendingRequest>,
+    paste_infos: Vec<PasteInfo>,
+    paste_infos_stale: bool,
+    pending_search_request: Option<PendingRequest>,
+    search_query: String,
+    // None unless the sidebar shows search results instead of all pastes
+    search_matches: Option<Vec<SearchMatch>>,
+    list_options: ListOptions,
+    tag_filter: String,
+    // where the folder whose menu is open gets moved to
+    move_to: String,
+    pending_history_request: Option<PendingRequest>,
+    // (name, revisions) of the paste whose history panel is open
+    history: Option<(String, Vec<RevisionInfo>)>,
+    pending_revision_request: Option<PendingRequest>,
+    revision_preview: Option<(u64, Zeroizing<String>)>,
     name: String,
+    rename_to: String,
+    append_text: String,
+    // comma separated tags of the paste in the editor
+    tags: Zeroizing<String>,
+    // limits for the next new paste, 0 reads meaning no limit
+    expires_in: Option<Duration>,
+    max_reads: u32,
     content: String,
+    // (name, revision) of the paste that the editor content is based on
+    base_revision: Option<(String, u64)>,
+    conflict: Option<Conflict>,
+    merge: Option<Merge>,
+    pending_merge_base_request: Option<PendingRequest>,
+    // (name, attachments) of the paste in the editor
+    attachments: Option<(String, Vec<AttachmentInfo>)>,
+    pending_attachments_request: Option<PendingRequest>,
+    attach_path: String,
+    save_path: String,
+    uploads: Vec<Upload>,
+    download: Option<Download>,
+    saved_path: Option<PathBuf>,
+    // (name, grants) of the paste in the editor
+    grants: Option<(String, Vec<Grant>)>,
+    pending_grants_request: Option<PendingRequest>,
+    // the grantee's public key as copied by its client
+    grantee: String,
+    permission: Permission,
+    cover: Option<Cover>,
 }
 
 const PENDING_REQUEST_RETRY_PERIOD: Duration = Duration::from_secs(3);
 
 const PENDING_GET_REQUEST_TIMEOUT: Duration = Duration::from_secs(8);
 
+const PENDING_READ_REQUEST_TIMEOUT: Duration = Duration::from_secs(8);
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(20);
+
+const MAX_FAILURES: usize = 8;
+
+const MAX_WRITES: usize = 8;
+
+const EXPIRES_IN_CHOICES: [Option<Duration>; 5] = [
+    None,
+    Some(Duration::from_secs(10 * 60)),
+    Some(Duration::from_secs(60 * 60)),
+    Some(Duration::from_secs(24 * 60 * 60)),
+    Some(Duration::from_secs(7 * 24 * 60 * 60)),
+];
+
 fn pending_label(ui: &mut Ui, text: &str) {
     ui.centered_and_justified(|ui| {
-        ui.label(text);
+        ui.horizontal(|ui| {
+            ui.add(Spinner::new());
+            ui.label(text);
+        });
     });
 }
 
@@ -61,8 +380,16 @@ fn available_width(ui: &Ui, style: &TextStyle) -> Vec2 {
     Vec2::new(ui.available_width(), ui.text_style_height(style))
 }
 
+impl Merge {
+    fn set_base(&mut self, base: &str) {
+        let hunks = merge::merge(base, &self.local.content, &self.current.content);
+        self.result = Zeroizing::new(merge::join(&hunks));
+        self.hunks = Some(hunks);
+    }
+}
+
 impl App {
-    fn new(cc: &eframe::CreationContext<'_>) -> anyhow::Result<Self> {
+    fn new(cc: &eframe::CreationContext<'_>) -> Self {
         let mut fonts = FontDefinitions::default();
         fonts.font_data.insert("stalinist".into(), {
             let mut font_data = FontData::from_static(include_bytes!("StalinistOne-Regular.ttf"));
@@ -79,94 +406,331 @@ impl App {
             .insert(0, "stalinist".to_owned());
         cc.egui_ctx.set_fonts(fonts);
 
-        let mut rng = thread_rng();
-        let msgs = gist::collect()?;
-        let rsa_private_key = rsa_private_key(&mut rng);
-        let rsa_public_key = rsa_private_key.to_public_key();
-
-        let session_key = if let Some((_, encrypted_session_key)) = msgs
-            .iter()
-            .flat_map(|(_, msg)| msg.as_greet_response())
-            .find(|(request, _)| request.0 == rsa_public_key)
-        {
-            Some(decrypt_aes_key(&encrypted_session_key, &rsa_private_key)?)
-        } else {
-            dbg!(gist::insert(&msg::Msg::GreetRequest(GreetRequest(
-                rsa_public_key
-            )))?);
-            None
-        };
+        let mut worker = Worker::spawn(cc.egui_ctx.clone());
+        worker.send(Command::Collect(HashSet::new()));
 
-        Ok(Self {
-            rng,
-            session_key,
-            msgs,
+        Self {
+            rng: thread_rng(),
+            worker,
+            rsa_private_key: None,
+            session_key: None,
+            greet_request_sent: false,
+            msgs: Vec::new(),
+            collecting: true,
+            quarantine: HashSet::new(),
+            failures: Vec::new(),
             pending_request_retry_instant: Instant::now(),
             pending_get_request_start_instant: Instant::now(),
             pending_get_request: None,
+            writes: Vec::new(),
+            pending_list_request: None,
+            paste_infos: Vec::new(),
+            paste_infos_stale: true,
+            pending_search_request: None,
+            search_query: String::new(),
+            search_matches: None,
+            list_options: ListOptions::default(),
+            tag_filter: String::new(),
+            move_to: String::new(),
+            pending_history_request: None,
+            history: None,
+            pending_revision_request: None,
+            revision_preview: None,
             name: "Имя новой записи".into(),
+            rename_to: String::new(),
+            append_text: String::new(),
+            tags: Zeroizing::new(String::new()),
+            expires_in: None,
+            max_reads: 0,
+            base_revision: None,
+            conflict: None,
+            merge: None,
+            pending_merge_base_request: None,
+            attachments: None,
+            pending_attachments_request: None,
+            attach_path: String::new(),
+            save_path: String::new(),
+            uploads: Vec::new(),
+            download: None,
+            saved_path: None,
+            grants: None,
+            pending_grants_request: None,
+            grantee: String::new(),
+            permission: Permission::Read,
+            cover: msg::cover_period().map(Cover::new),
             content: "Содержание новой записи".into(),
-        })
+        }
     }
 
-    fn show_pending_greet_request(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
-        pending_label(ui, "Получаем сессионный ключ ...");
-        if self.pending_request_retry_instant.elapsed() >= PENDING_REQUEST_RETRY_PERIOD {
-            self.msgs = gist::collect()?;
-            let rsa_private_key = rsa_private_key(&mut self.rng);
-            let greet_request = GreetRequest(rsa_private_key.to_public_key());
-            if let Some((_, encryted_session_key)) = self
-                .msgs
-                .iter()
-                .filter_map(|(_, msg)| msg.as_greet_response())
-                .find(|greet_response| greet_response.0 == greet_request)
-            {
-                self.session_key = Some(decrypt_aes_key(&encryted_session_key, &rsa_private_key)?);
-            } else {
-                self.pending_request_retry_instant = Instant::now();
+    fn fail(&mut self, error: anyhow::Error, retry: Option<Command>) {
+        let message = format!("{error:#}");
+        self.failures.retain(|failure| failure.message != message);
+        self.failures.push(Failure { message, retry });
+        if self.failures.len() > MAX_FAILURES {
+            self.failures.remove(0);
+        }
+    }
+
+    fn surface(&mut self, result: anyhow::Result<()>) {
+        if let Err(error) = result {
+            self.fail(error, None);
+        }
+    }
+
+    // Loaded from disk, or generated, once. rsa zeroizes the key on drop, but
+    // its numbers live in ordinary heap allocations that cannot be mlocked
+    // short of a locking global allocator.
+    fn rsa_private_key(&mut self) -> anyhow::Result<&RsaPrivateKey> {
+        if self.rsa_private_key.is_none() {
+            self.rsa_private_key = Some(load_rsa_private_key(&mut self.rng)?);
+        }
+        Ok(self.rsa_private_key.as_ref().unwrap())
+    }
+
+    fn send_greet_request(&mut self) -> anyhow::Result<()> {
+        let rsa_public_key = self.rsa_private_key()?.to_public_key();
+        self.worker
+            .send(Command::Insert(Box::new(Msg::GreetRequest(GreetRequest(
+                rsa_public_key,
+            )))));
+        self.greet_request_sent = true;
+        Ok(())
+    }
+
+    fn remove_msg(&mut self, gist_id: gist::GistId) {
+        self.msgs
+            .retain(|(other_gist_id, _)| *other_gist_id != gist_id);
+        self.worker.send(Command::Remove(gist_id));
+    }
+
+    // The server no longer knows our session, so its stale greet response has to
+    // go before it will answer a new greet request for the same RSA key.
+    fn greet_again(&mut self) -> anyhow::Result<()> {
+        let rsa_public_key = self.rsa_private_key()?.to_public_key();
+        let stale_gist_ids: Vec<_> = self
+            .msgs
+            .iter()
+            .filter(|(_, msg)| {
+                msg.as_greet_response()
+                    .is_some_and(|(request, _)| request.0 == rsa_public_key)
+            })
+            .map(|(gist_id, _)| gist_id.clone())
+            .collect();
+        for gist_id in stale_gist_ids {
+            self.remove_msg(gist_id);
+        }
+        self.session_key = None;
+        self.paste_infos_stale = true;
+        self.send_greet_request()
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Collected(Ok(collected)) => {
+                self.collecting = false;
+                self.msgs.clear();
+                for (gist_id, msg) in collected {
+                    match msg {
+                        Ok(msg) => self.msgs.push((gist_id, msg)),
+                        // such as a msg of an older version, which would fail
+                        // every collect if it were fetched again
+                        Err(error) if error.is::<serde_json::Error>() => {
+                            if self.quarantine.insert(gist_id.clone()) {
+                                self.fail(
+                                    error.context(format!("skipped undecodable gist {gist_id}")),
+                                    None,
+                                );
+                            }
+                        }
+                        Err(error) => self.fail(
+                            error.context(format!("failed to fetch gist {gist_id}")),
+                            None,
+                        ),
+                    }
+                }
+                if self.session_key.is_none() && !self.greet_request_sent {
+                    let greeted = self.receive_session_key().and_then(|()| {
+                        if self.session_key.is_none() {
+                            self.send_greet_request()
+                        } else {
+                            Ok(())
+                        }
+                    });
+                    self.surface(greeted);
+                }
+            }
+            Event::Collected(Err(error)) => {
+                self.collecting = false;
+                self.fail(error, Some(Command::Collect(self.quarantine.clone())));
+            }
+            Event::Inserted(msg, Ok(gist_id)) => self.msgs.push((gist_id, msg)),
+            Event::Inserted(msg, Err(error)) => {
+                self.fail(error, Some(Command::Insert(Box::new(msg))))
+            }
+            Event::Removed(gist_id, Ok(())) => {
+                self.msgs
+                    .retain(|(other_gist_id, _)| *other_gist_id != gist_id);
+            }
+            Event::Removed(gist_id, Err(error)) => {
+                self.fail(error, Some(Command::Remove(gist_id)));
+            }
+            Event::Pushed(gist_id, msg) => {
+                if self
+                    .msgs
+                    .iter()
+                    .all(|(other_gist_id, _)| *other_gist_id != gist_id)
+                {
+                    self.msgs.push((gist_id, msg));
+                }
             }
+            Event::Stopped(error) => self.fail(error, None),
+        }
+    }
+
+    fn collect_if_retry_period_elapsed(&mut self) {
+        if !self.collecting
+            && self.pending_request_retry_instant.elapsed() >= PENDING_REQUEST_RETRY_PERIOD
+        {
+            self.worker.send(Command::Collect(self.quarantine.clone()));
+            self.collecting = true;
+            self.pending_request_retry_instant = Instant::now();
+        }
+    }
+
+    fn receive_session_key(&mut self) -> anyhow::Result<()> {
+        let greet_request = GreetRequest(self.rsa_private_key()?.to_public_key());
+        if let Some((_, encryted_session_key)) = self
+            .msgs
+            .iter()
+            .filter_map(|(_, msg)| msg.as_greet_response())
+            .find(|greet_response| greet_response.0 == greet_request)
+            .cloned()
+        {
+            let session_key = decrypt_aes_key(&encryted_session_key, self.rsa_private_key()?)?;
+            self.session_key = Some(session_key);
         }
         Ok(())
     }
 
-    fn show_new_rsa_key(&mut self, ui: &mut Ui) {
+    fn show_new_rsa_key(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
+        let mut result = Ok(());
         ui.horizontal(|ui| {
             ui.group(|ui| {
                 if ui.button("Новый RSA ключ").clicked() {
-                    generate_rsa_private_key(&mut self.rng);
                     self.session_key = None;
-                    gist::insert(&Msg::GreetRequest(GreetRequest(
-                        rsa_private_key(&mut self.rng).to_public_key(),
-                    )))
-                    .unwrap();
+                    self.paste_infos_stale = true;
+                    result = generate_rsa_private_key(&mut self.rng).and_then(|key| {
+                        self.rsa_private_key = Some(key);
+                        self.send_greet_request()
+                    });
                 };
-                ui.add_sized(
-                    available_width(ui, &TextStyle::Body),
-                    Label::new(format!("{} ...", {
-                        serde_json::to_string(&rsa_private_key(&mut self.rng))
-                            .unwrap()
-                            .trim_start_matches(|ch: char| !ch.is_numeric())
-                            .char_range(0..30)
-                    })),
-                );
+                if ui.button("Скопировать открытый ключ").clicked() {
+                    result = self.rsa_private_key().and_then(|key| {
+                        ui.output().copied_text = serde_json::to_string(&key.to_public_key())?;
+                        Ok(())
+                    });
+                }
+                // the public key starts with the same digits and leaves no
+                // serialized private key behind
+                let text = match self
+                    .rsa_private_key()
+                    .and_then(|key| Ok(serde_json::to_string(&key.to_public_key())?))
+                {
+                    Ok(key) => describe_key(&key),
+                    Err(_) => "RSA ключ недоступен".into(),
+                };
+                ui.add_sized(available_width(ui, &TextStyle::Body), Label::new(text));
             });
         });
+        result
     }
 
     fn msgs_contain_encrypted_request(&self, encrypted_request: &EncryptedActionRequest) -> bool {
         self.msgs
             .iter()
             .filter_map(|(_, msg)| msg.as_encrypted_action_request())
-            .find(|other_encrypted_request| *other_encrypted_request == encrypted_request)
-            .is_some()
+            .any(|other_encrypted_request| other_encrypted_request == encrypted_request)
     }
 
     fn pastebin_insert_if_no_msg_contains_encrypted_request(
-        &self,
+        &mut self,
         encrypted_request: EncryptedActionRequest,
-    ) -> anyhow::Result<()> {
+    ) {
         if !self.msgs_contain_encrypted_request(&encrypted_request) {
-            gist::insert(&Msg::EncryptedActionRequest(encrypted_request))?;
+            self.worker
+                .send(Command::Insert(Box::new(Msg::EncryptedActionRequest(
+                    encrypted_request,
+                ))));
+        }
+    }
+
+    fn session_key(&self) -> anyhow::Result<AesKey> {
+        self.session_key
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("no session key has been received yet"))
+    }
+
+    fn send_action(&mut self, request: ActionRequest) -> anyhow::Result<EncryptedActionRequest> {
+        let session_key = self.session_key()?;
+        if let Some(cover) = &mut self.cover {
+            let encrypted_request = request.seal(&session_key)?;
+            cover.queue(encrypted_request.clone());
+            return Ok(encrypted_request);
+        }
+        let encrypted_request = request.encrypt(&session_key)?;
+        self.pastebin_insert_if_no_msg_contains_encrypted_request(encrypted_request.clone());
+        Ok(encrypted_request)
+    }
+
+    // Whether the request still waits for a cover slot. Timeouts only start
+    // once it is sent.
+    fn is_queued(&self, request: &EncryptedActionRequest) -> bool {
+        self.cover
+            .as_ref()
+            .is_some_and(|cover| cover.is_queued(request))
+    }
+
+    fn cancel_queued(&mut self, request: &EncryptedActionRequest) {
+        if let Some(cover) = &mut self.cover {
+            cover.cancel(request);
+        }
+    }
+
+    // Sends the next queued request, or a dummy one, whenever a cover slot is
+    // due, and drops the answers to dummy requests.
+    fn poll_cover(&mut self) -> anyhow::Result<()> {
+        let (Some(cover), Some(session_key)) = (&mut self.cover, self.session_key.clone()) else {
+            return Ok(());
+        };
+        let answered: Vec<_> = self
+            .msgs
+            .iter()
+            .filter(|(_, msg)| {
+                msg.answered_request()
+                    .is_some_and(|request| cover.remove_dummy(request))
+            })
+            .map(|(gist_id, _)| gist_id.clone())
+            .collect();
+        let mut encrypted_request = None;
+        if cover.take_slot(&mut self.rng) {
+            encrypted_request = Some(match cover.pop() {
+                Some(encrypted_request) => encrypted_request,
+                None => {
+                    let dummy = ActionRequest::Cover.seal(&session_key)?;
+                    cover.add_dummy(dummy.clone());
+                    dummy
+                }
+            });
+        }
+        let awaits_dummies = cover.has_dummies();
+        for gist_id in answered {
+            self.remove_msg(gist_id);
+        }
+        if let Some(encrypted_request) = encrypted_request {
+            self.pastebin_insert_if_no_msg_contains_encrypted_request(encrypted_request);
+        }
+        if awaits_dummies {
+            self.collect_if_retry_period_elapsed();
         }
         Ok(())
     }
@@ -175,141 +739,1535 @@ impl App {
         Paste {
             name: self.name.clone(),
             content: self.content.clone(),
+            tags: parse_tags(&self.tags),
         }
     }
 
-    fn show_actions(&mut self, ui: &mut Ui, session_key: &AesKey) {
+    fn editor_base_revision(&self) -> Option<u64> {
+        self.base_revision
+            .as_ref()
+            .filter(|(name, _)| *name == self.name)
+            .map(|(_, revision)| *revision)
+    }
+
+    fn show_actions(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
+        let enabled = self.session_key.is_some();
+        let mut request = None;
+        let mut history_clicked = false;
         ui.horizontal(|ui| {
-            if ui.button("Новая запись").clicked() {
-                self.msgs = gist::collect().unwrap();
-                let encrypted_request = ActionRequest::New(self.clone_paste())
-                    .encrypt(session_key)
-                    .unwrap();
-                self.pastebin_insert_if_no_msg_contains_encrypted_request(encrypted_request)
-                    .unwrap();
-            }
-            if ui.button("Редактировать запись").clicked() {
-                self.msgs = gist::collect().unwrap();
-                let encrypted_request = ActionRequest::Mut(self.clone_paste())
-                    .encrypt(session_key)
-                    .unwrap();
-                self.pastebin_insert_if_no_msg_contains_encrypted_request(encrypted_request)
-                    .unwrap();
+            if ui
+                .add_enabled(enabled, Button::new("Новая запись"))
+                .clicked()
+            {
+                request = Some(ActionRequest::New {
+                    paste: self.clone_paste(),
+                    expiry: Expiry {
+                        expires_at: self
+                            .expires_in
+                            .map(|expires_in| SystemTime::now() + expires_in),
+                        max_reads: (self.max_reads > 0).then_some(self.max_reads),
+                    },
+                });
             }
             if ui
-                .add_sized(
-                    available_width(&ui, &TextStyle::Button),
-                    Button::new("Удалить запись"),
+                .add_enabled(enabled, Button::new("Редактировать запись"))
+                .clicked()
+            {
+                request = Some(ActionRequest::Mut {
+                    paste: self.clone_paste(),
+                    base_revision: self.editor_base_revision(),
+                });
+            }
+            if ui.add_enabled(enabled, Button::new("История")).clicked() {
+                history_clicked = true;
+            }
+            if ui
+                .add_enabled_ui(enabled, |ui| {
+                    ui.add_sized(
+                        available_width(ui, &TextStyle::Button),
+                        Button::new("Удалить запись"),
+                    )
+                })
+                .inner
+                .clicked()
+            {
+                request = Some(ActionRequest::Remove {
+                    name: self.name.clone(),
+                });
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Новая запись удалится через");
+            ComboBox::from_id_source("expires_in")
+                .selected_text(describe_expires_in(self.expires_in))
+                .show_ui(ui, |ui| {
+                    for expires_in in EXPIRES_IN_CHOICES {
+                        ui.selectable_value(
+                            &mut self.expires_in,
+                            expires_in,
+                            describe_expires_in(expires_in),
+                        );
+                    }
+                });
+            ui.label("или после прочтений");
+            ui.add(DragValue::new(&mut self.max_reads).clamp_range(0..=100));
+            if self.max_reads == 0 {
+                ui.label("(без ограничения)");
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(
+                    enabled && !self.rename_to.is_empty() && self.rename_to != self.name,
+                    Button::new("Переименовать в"),
                 )
                 .clicked()
             {
-                self.msgs = gist::collect().unwrap();
-                let encrypted_request = ActionRequest::Remove {
+                request = Some(ActionRequest::Rename {
+                    from: self.name.clone(),
+                    to: self.rename_to.clone(),
+                });
+            }
+            ui.add_sized(
+                available_width(ui, &TextStyle::Body),
+                TextEdit::singleline(&mut self.rename_to),
+            );
+        });
+        ui.horizontal(|ui| {
+            let mut appended = ui
+                .add_enabled(
+                    enabled && !self.append_text.is_empty(),
+                    Button::new("Дописать"),
+                )
+                .clicked();
+            appended |= ui
+                .add_sized(
+                    available_width(ui, &TextStyle::Body),
+                    TextEdit::singleline(&mut self.append_text).hint_text("Быстрая запись"),
+                )
+                .lost_focus()
+                && ui.input().key_pressed(Key::Enter)
+                && enabled
+                && !self.append_text.is_empty();
+            if appended {
+                request = Some(ActionRequest::Append {
                     name: self.name.clone(),
-                }
-                .encrypt(session_key)
-                .unwrap();
-                self.pastebin_insert_if_no_msg_contains_encrypted_request(encrypted_request)
-                    .unwrap();
+                    text: mem::take(&mut self.append_text),
+                });
             }
         });
+        if history_clicked {
+            self.send_history_request(self.name.clone())?;
+        }
+        if let Some(request) = request {
+            self.send_write(request)?;
+        }
+        Ok(())
     }
 
-    fn show_get_and_name(&mut self, ui: &mut Ui, session_key: &AesKey) {
+    fn show_get_and_name(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
+        let mut clicked = false;
         ui.horizontal(|ui| {
-            if ui.button("Найти запись").clicked() {
-                let encrypted_request = ActionRequest::Get {
-                    name: self.name.clone(),
-                }
-                .encrypt(session_key)
-                .unwrap();
-                self.pastebin_insert_if_no_msg_contains_encrypted_request(
-                    encrypted_request.clone(),
-                )
-                .unwrap();
-                self.pending_get_request_start_instant = Instant::now();
-                self.pending_get_request = Some(encrypted_request);
-            }
+            clicked = ui
+                .add_enabled(self.session_key.is_some(), Button::new("Найти запись"))
+                .clicked();
             ui.add_sized(
                 available_width(ui, &TextStyle::Body),
                 TextEdit::singleline(&mut self.name),
             );
         });
+        ui.horizontal(|ui| {
+            ui.label("Метки");
+            ui.add_sized(
+                available_width(ui, &TextStyle::Body),
+                TextEdit::singleline(&mut *self.tags).hint_text("через запятую"),
+            );
+        });
+        if let Some(expiry) = self
+            .paste_infos
+            .iter()
+            .find(|paste_info| paste_info.name == self.name)
+            .and_then(describe_expiry)
+        {
+            ui.label(RichText::new(expiry).color(Color32::YELLOW));
+        }
+        if clicked {
+            self.send_get_request()?;
+        }
+        Ok(())
     }
 
-    fn show_pending_get_request(
+    fn send_get_request(&mut self) -> anyhow::Result<()> {
+        let encrypted_request = self.send_action(ActionRequest::Get {
+            name: self.name.clone(),
+        })?;
+        self.pending_get_request_start_instant = Instant::now();
+        self.pending_get_request = Some(encrypted_request);
+        Ok(())
+    }
+
+    fn send_request(&mut self, request: ActionRequest) -> anyhow::Result<PendingRequest> {
+        let session_key = self.session_key()?;
+        let encrypted_request = self.send_action(request.clone())?;
+        Ok(PendingRequest {
+            request,
+            encrypted_request,
+            session_key,
+            sent_instant: Instant::now(),
+        })
+    }
+
+    // Returns the response once it arrives, or the request while it is still
+    // unanswered, resent if the session key was rotated in the meantime.
+    fn poll_request(
         &mut self,
-        ui: &mut Ui,
-        session_key: &AesKey,
-    ) -> anyhow::Result<()> {
-        pending_label(ui, &format!("Получаем запись \"{}\" ...", self.name));
-        if self.pending_get_request_start_instant.elapsed() < PENDING_GET_REQUEST_TIMEOUT {
-            if self.pending_request_retry_instant.elapsed() >= PENDING_REQUEST_RETRY_PERIOD {
-                self.msgs = gist::collect()?;
-                let pending_get_request = self.pending_get_request.as_ref().unwrap();
-                if let Some((gist_id, (_, encrypted_response))) = self
-                    .msgs
-                    .iter()
-                    .filter_map(|(api_paste_key, msg)| {
-                        msg.as_encrypted_action_response()
-                            .map(|encrypted_response| (api_paste_key, encrypted_response))
-                    })
-                    .find(|(_, encrypted_response)| &encrypted_response.0 == pending_get_request)
-                {
-                    match encrypted_response {
-                        either::Either::Left(paste) => {
-                            if let Some(paste) = paste.as_ref() {
-                                self.name = paste.decrypt_name(session_key)?;
-                                self.content = paste.decrypt_content(session_key)?;
-                                self.pending_get_request = None;
-                            } else {
-                                anyhow::bail!("EncryptedActionRequest yielded no paste");
-                            }
-                        }
-                        either::Either::Right(encrypted_session_key) => {
-                            let rsa_private_key = rsa_private_key(&mut self.rng);
-                            self.session_key =
-                                Some(decrypt_aes_key(encrypted_session_key, &rsa_private_key)?);
-                            gist::remove(&gist_id)?;
-                        }
-                    }
-                }
+        mut pending: PendingRequest,
+    ) -> anyhow::Result<Either<ActionResponse, PendingRequest>> {
+        match self.take_answer(&pending.encrypted_request, &pending.session_key)? {
+            Some(Answer::Response(ActionResponse::Error(error))) => Err(error.into()),
+            Some(Answer::Response(response)) => Ok(Either::Left(response)),
+            Some(Answer::Rekeyed) => Ok(Either::Right(self.send_request(pending.request)?)),
+            None if self.is_queued(&pending.encrypted_request) => {
+                pending.sent_instant = Instant::now();
+                Ok(Either::Right(pending))
+            }
+            None if pending.sent_instant.elapsed() >= PENDING_READ_REQUEST_TIMEOUT => {
+                anyhow::bail!("no response to the request within {PENDING_READ_REQUEST_TIMEOUT:?}")
+            }
+            None => {
+                self.collect_if_retry_period_elapsed();
+                Ok(Either::Right(pending))
             }
-        } else {
-            self.pending_get_request = None;
         }
+    }
 
+    fn poll_list_request(&mut self) -> anyhow::Result<()> {
+        if self.paste_infos_stale
+            && self.session_key.is_some()
+            && self.pending_list_request.is_none()
+        {
+            self.paste_infos_stale = false;
+            let nonce = self.rng.gen();
+            self.pending_list_request = Some(self.send_request(ActionRequest::List {
+                nonce,
+                options: self.list_options.clone(),
+            })?);
+        }
+        let Some(pending) = self.pending_list_request.take() else {
+            return Ok(());
+        };
+        match self.poll_request(pending)? {
+            Either::Left(ActionResponse::List(paste_infos)) => self.paste_infos = paste_infos,
+            Either::Left(response) => {
+                anyhow::bail!("unexpected response to the list request: {response:?}")
+            }
+            Either::Right(pending) => self.pending_list_request = Some(pending),
+        }
         Ok(())
     }
-}
 
-impl eframe::App for App {
-    fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
-        CentralPanel::default().show(ctx, |ui| {
-            if let Some(session_key) = self.session_key.clone() {
-                if self.pending_get_request.is_some() {
-                    self.show_pending_get_request(ui, &session_key).unwrap();
-                } else {
-                    self.show_new_rsa_key(ui);
-                    ui.group(|ui| {
-                        self.show_actions(ui, &session_key);
-                        self.show_get_and_name(ui, &session_key);
-                    });
-                    ui.add_sized(ui.available_size(), TextEdit::multiline(&mut self.content));
-                }
-            } else {
-                self.show_pending_greet_request(ui).unwrap();
+    fn poll_search_request(&mut self) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_search_request.take() else {
+            return Ok(());
+        };
+        match self.poll_request(pending)? {
+            Either::Left(ActionResponse::Matches(search_matches)) => {
+                self.search_matches = Some(search_matches);
             }
-        });
-        ctx.request_repaint_after(PENDING_REQUEST_RETRY_PERIOD + Duration::from_secs(1));
+            Either::Left(response) => {
+                anyhow::bail!("unexpected response to the search request: {response:?}")
+            }
+            Either::Right(pending) => self.pending_search_request = Some(pending),
+        }
+        Ok(())
     }
-}
 
-fn main() {
+    fn send_history_request(&mut self, name: String) -> anyhow::Result<()> {
+        self.pending_history_request = Some(self.send_request(ActionRequest::History { name })?);
+        Ok(())
+    }
+
+    fn poll_history_request(&mut self) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_history_request.take() else {
+            return Ok(());
+        };
+        let request = pending.request.clone();
+        match (request, self.poll_request(pending)?) {
+            (ActionRequest::History { name }, Either::Left(ActionResponse::History(revisions))) => {
+                if self.history.as_ref().map(|(other_name, _)| other_name) != Some(&name) {
+                    self.revision_preview = None;
+                }
+                self.history = Some((name, revisions));
+            }
+            (_, Either::Left(response)) => {
+                anyhow::bail!("unexpected response to the history request: {response:?}")
+            }
+            (_, Either::Right(pending)) => self.pending_history_request = Some(pending),
+        }
+        Ok(())
+    }
+
+    fn poll_revision_request(&mut self) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_revision_request.take() else {
+            return Ok(());
+        };
+        let request = pending.request.clone();
+        match (request, self.poll_request(pending)?) {
+            (
+                ActionRequest::Revision { revision, .. },
+                Either::Left(ActionResponse::Paste { mut paste, .. }),
+            ) => {
+                self.revision_preview =
+                    Some((revision, Zeroizing::new(mem::take(&mut paste.content))));
+            }
+            (_, Either::Left(response)) => {
+                anyhow::bail!("unexpected response to the revision request: {response:?}")
+            }
+            (_, Either::Right(pending)) => self.pending_revision_request = Some(pending),
+        }
+        Ok(())
+    }
+
+    fn show_history(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
+        let Some((name, revisions)) = &self.history else {
+            return Ok(());
+        };
+        let name = name.clone();
+        let enabled = self.session_key.is_some();
+        let mut closed = false;
+        let mut previewed = None;
+        let mut restored = None;
+        ui.horizontal(|ui| {
+            closed = ui.button("✖").clicked();
+            ui.heading(format!("История \"{name}\""));
+            if self.pending_history_request.is_some() || self.pending_revision_request.is_some() {
+                ui.add(Spinner::new());
+            }
+        });
+        ui.separator();
+        ScrollArea::vertical()
+            .id_source("revisions")
+            .max_height(ui.available_height() / 2.0)
+            .show(ui, |ui| {
+                for (index, revision) in revisions.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        if ui.add_enabled(enabled, Button::new("Показать")).clicked() {
+                            previewed = Some(revision.revision);
+                        }
+                        if index == 0 {
+                            ui.label("текущая");
+                        } else if ui
+                            .add_enabled(enabled, Button::new("Восстановить"))
+                            .clicked()
+                        {
+                            restored = Some(revision.revision);
+                        }
+                        ui.label(format!(
+                            "№{}: {} байт, {}",
+                            revision.revision,
+                            revision.len,
+                            format_modified(revision.modified)
+                        ));
+                    });
+                }
+            });
+        if let Some((revision, content)) = &self.revision_preview {
+            ui.separator();
+            ui.label(format!("Ревизия №{revision}"));
+            ScrollArea::vertical()
+                .id_source("revision_preview")
+                .show(ui, |ui| {
+                    ui.add_sized(
+                        ui.available_size(),
+                        TextEdit::multiline(&mut content.as_str()),
+                    );
+                });
+        }
+        if closed {
+            self.history = None;
+            self.revision_preview = None;
+        }
+        if let Some(revision) = previewed {
+            self.pending_revision_request = Some(self.send_request(ActionRequest::Revision {
+                name: name.clone(),
+                revision,
+            })?);
+        }
+        if let Some(revision) = restored {
+            self.send_write(ActionRequest::Restore { name, revision })?;
+        }
+        Ok(())
+    }
+
+    // Keeps what the ui shows in line with a write the server has just applied.
+    fn write_done(
+        &mut self,
+        request: &ActionRequest,
+        response: ActionResponse,
+    ) -> anyhow::Result<()> {
+        self.paste_infos_stale = true;
+        if let (
+            ActionRequest::New { paste, .. } | ActionRequest::Mut { paste, .. },
+            ActionResponse::Written { revision },
+        ) = (request, response)
+        {
+            if self.name == paste.name {
+                self.base_revision = Some((paste.name.clone(), revision));
+            }
+        }
+        if let ActionRequest::Rename { from, to } = request {
+            if self.name == *from {
+                set_text(&mut self.name, to.clone());
+            }
+        }
+        if let ActionRequest::MoveFolder { from, to } = request {
+            if let Some(name) = msg::moved_name(&self.name, from, to) {
+                set_text(&mut self.name, name);
+            }
+        }
+        if let ActionRequest::Attach { name, chunk } = request {
+            self.continue_upload(name, chunk)?;
+        }
+        if let ActionRequest::Attach { name, .. } | ActionRequest::Detach { name, .. } = request {
+            if self.attachments.as_ref().map(|(other_name, _)| other_name) == Some(name) {
+                self.send_attachments_request(name.clone())?;
+            }
+        }
+        if let ActionRequest::Grant { name, .. } | ActionRequest::Revoke { name, .. } = request {
+            if self.grants.as_ref().map(|(other_name, _)| other_name) == Some(name) {
+                self.send_grants_request(name.clone())?;
+            }
+        }
+        let name = match request {
+            ActionRequest::Mut { paste, .. } => &paste.name,
+            ActionRequest::Restore { name, .. } => name,
+            ActionRequest::Append { name, .. } => name,
+            _ => return Ok(()),
+        };
+        if matches!(request, ActionRequest::Restore { .. }) && self.name == *name {
+            self.send_get_request()?;
+        }
+        if self.history.as_ref().map(|(other_name, _)| other_name) == Some(name) {
+            self.send_history_request(name.clone())?;
+        }
+        Ok(())
+    }
+
+    fn show_search(&mut self, ui: &mut Ui, enabled: bool) -> anyhow::Result<()> {
+        let mut searched = false;
+        ui.horizontal(|ui| {
+            if self.pending_search_request.is_some() {
+                ui.add(Spinner::new());
+            } else {
+                searched = ui
+                    .add_enabled(
+                        enabled && !self.search_query.trim().is_empty(),
+                        Button::new("Искать"),
+                    )
+                    .clicked();
+            }
+            if self.search_matches.is_some() && ui.button("✖").clicked() {
+                self.search_matches = None;
+            }
+            searched |= ui
+                .add_enabled(enabled, TextEdit::singleline(&mut self.search_query))
+                .lost_focus()
+                && ui.input().key_pressed(Key::Enter)
+                && !self.search_query.trim().is_empty();
+        });
+        if searched && self.pending_search_request.is_none() {
+            self.pending_search_request = Some(self.send_request(ActionRequest::Search {
+                query: self.search_query.clone(),
+                options: self.list_options.clone(),
+            })?);
+        }
+        Ok(())
+    }
+
+    // Changing the options reloads the list, search results follow on the next search.
+    fn show_list_options(&mut self, ui: &mut Ui) {
+        let options = self.list_options.clone();
+        ui.horizontal(|ui| {
+            ComboBox::from_id_source("sort_by")
+                .selected_text(describe_sort_by(self.list_options.sort_by))
+                .show_ui(ui, |ui| {
+                    for sort_by in [SortBy::Name, SortBy::Created, SortBy::Modified, SortBy::Len] {
+                        ui.selectable_value(
+                            &mut self.list_options.sort_by,
+                            sort_by,
+                            describe_sort_by(sort_by),
+                        );
+                    }
+                });
+            ui.checkbox(&mut self.list_options.descending, "по убыванию");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Метки");
+            if ui
+                .add(TextEdit::singleline(&mut self.tag_filter).hint_text("все"))
+                .lost_focus()
+            {
+                self.list_options.tags = parse_tags(&self.tag_filter);
+            }
+        });
+        if !self.list_options.folder.is_empty() {
+            ui.horizontal(|ui| {
+                if ui.button("✖").clicked() {
+                    self.list_options.folder.clear();
+                }
+                ui.label(format!("Папка \"{}\"", self.list_options.folder));
+            });
+        }
+        if self.list_options != options {
+            self.paste_infos_stale = true;
+        }
+    }
+
+    fn show_paste_infos(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
+        let enabled = self.session_key.is_some() && self.pending_get_request.is_none();
+        ui.horizontal(|ui| {
+            ui.heading("Записи");
+            if self.pending_list_request.is_some() {
+                ui.add(Spinner::new());
+            } else if ui.add_enabled(enabled, Button::new("Обновить")).clicked() {
+                self.paste_infos_stale = true;
+            }
+        });
+        self.show_search(ui, enabled)?;
+        self.show_list_options(ui);
+        ui.separator();
+        let mut opened = None;
+        let mut request = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            if let Some(search_matches) = &self.search_matches {
+                if search_matches.is_empty() {
+                    ui.label("Ничего не найдено");
+                }
+                for search_match in search_matches {
+                    let text = format!(
+                        "{}\n{}",
+                        describe_paste_info(&search_match.info.name, &search_match.info),
+                        search_match.snippet
+                    );
+                    if ui
+                        .add_enabled(
+                            enabled,
+                            SelectableLabel::new(search_match.info.name == self.name, text),
+                        )
+                        .clicked()
+                    {
+                        opened = Some(search_match.info.name.clone());
+                    }
+                }
+                return;
+            }
+            let root = Folder::new(&self.paste_infos);
+            self.show_folder(ui, &root, "", enabled, &mut opened, &mut request);
+        });
+        if let Some(name) = opened {
+            set_text(&mut self.name, name);
+            self.send_get_request()?;
+        }
+        if let Some(request) = request {
+            self.send_write(request)?;
+        }
+        Ok(())
+    }
+
+    fn show_folder(
+        &mut self,
+        ui: &mut Ui,
+        folder: &Folder,
+        path: &str,
+        enabled: bool,
+        opened: &mut Option<String>,
+        request: &mut Option<ActionRequest>,
+    ) {
+        for (name, subfolder) in &folder.folders {
+            let subpath = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}/{name}")
+            };
+            CollapsingHeader::new(format!("{name}/"))
+                .id_source(("folder", &subpath))
+                .default_open(true)
+                .show(ui, |ui| {
+                    self.show_folder(ui, subfolder, &subpath, enabled, opened, request)
+                })
+                .header_response
+                .context_menu(|ui| self.show_folder_menu(ui, &subpath, enabled, request));
+        }
+        for (name, paste_info) in &folder.pastes {
+            let text = describe_paste_info(name, paste_info);
+            if ui
+                .add_enabled(
+                    enabled,
+                    SelectableLabel::new(paste_info.name == self.name, text),
+                )
+                .clicked()
+            {
+                *opened = Some(paste_info.name.clone());
+            }
+        }
+    }
+
+    fn show_folder_menu(
+        &mut self,
+        ui: &mut Ui,
+        folder: &str,
+        enabled: bool,
+        request: &mut Option<ActionRequest>,
+    ) {
+        if ui.button("Показывать только эту папку").clicked() {
+            self.list_options.folder = folder.into();
+            self.paste_infos_stale = true;
+            ui.close_menu();
+        }
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(
+                    enabled && self.move_to.trim_matches('/') != folder,
+                    Button::new("Переместить в"),
+                )
+                .clicked()
+            {
+                *request = Some(ActionRequest::MoveFolder {
+                    from: folder.into(),
+                    to: self.move_to.clone(),
+                });
+                ui.close_menu();
+            }
+            ui.add(TextEdit::singleline(&mut self.move_to).hint_text("корень"));
+        });
+        if ui
+            .add_enabled(enabled, Button::new("Удалить папку со всеми записями"))
+            .clicked()
+        {
+            *request = Some(ActionRequest::RemoveFolder {
+                folder: folder.into(),
+            });
+            ui.close_menu();
+        }
+    }
+
+    // Takes the server's answer to the request out of the mailbox, if there is one.
+    fn take_answer(
+        &mut self,
+        request: &EncryptedActionRequest,
+        session_key: &AesKey,
+    ) -> anyhow::Result<Option<Answer>> {
+        let Some((gist_id, msg)) = self
+            .msgs
+            .iter()
+            .find(|(_, msg)| msg.answered_request() == Some(request))
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        self.remove_msg(gist_id);
+        match msg {
+            Msg::EncryptedActionResponse((_, Either::Left(encrypted_response))) => Ok(Some(
+                Answer::Response(encrypted_response.decrypt(session_key)?),
+            )),
+            Msg::EncryptedActionResponse((_, Either::Right(encrypted_session_key))) => {
+                let session_key = decrypt_aes_key(&encrypted_session_key, self.rsa_private_key()?)?;
+                self.session_key = Some(session_key);
+                Ok(Some(Answer::Rekeyed))
+            }
+            Msg::Rejection((_, reason)) => {
+                if reason == RejectReason::UnknownSession {
+                    self.greet_again()?;
+                }
+                anyhow::bail!("the server rejected the request: {reason:?}")
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn show_pending_get_request(
+        &mut self,
+        ui: &mut Ui,
+        pending_get_request: &EncryptedActionRequest,
+        session_key: &AesKey,
+    ) -> anyhow::Result<()> {
+        pending_label(ui, &format!("Получаем запись \"{}\" ...", self.name));
+        if self.is_queued(pending_get_request) {
+            self.pending_get_request_start_instant = Instant::now();
+        }
+        if self.pending_get_request_start_instant.elapsed() >= PENDING_GET_REQUEST_TIMEOUT {
+            self.pending_get_request = None;
+            anyhow::bail!("no response to the get request within {PENDING_GET_REQUEST_TIMEOUT:?}");
+        }
+        self.collect_if_retry_period_elapsed();
+        let answer = self.take_answer(pending_get_request, session_key);
+        if !matches!(answer, Ok(None) | Ok(Some(Answer::Rekeyed))) {
+            self.pending_get_request = None;
+        }
+        match answer? {
+            Some(Answer::Response(ActionResponse::Paste {
+                mut paste,
+                revision,
+            })) => {
+                self.base_revision = Some((paste.name.clone(), revision));
+                // the read may have used up the paste
+                if self.paste_infos.iter().any(|paste_info| {
+                    paste_info.name == paste.name && paste_info.reads_left.is_some()
+                }) {
+                    self.paste_infos_stale = true;
+                }
+                // attachments and grants of pastes shared by others are not accessible
+                if self.is_shared(&paste.name) {
+                    self.attachments = None;
+                    self.grants = None;
+                } else {
+                    self.send_attachments_request(paste.name.clone())?;
+                    self.send_grants_request(paste.name.clone())?;
+                }
+                set_text(&mut self.name, mem::take(&mut paste.name));
+                set_text(&mut self.content, mem::take(&mut paste.content));
+                self.tags = Zeroizing::new(paste.tags.join(", "));
+            }
+            Some(Answer::Response(ActionResponse::Error(error))) => return Err(error.into()),
+            Some(Answer::Response(response)) => {
+                anyhow::bail!("unexpected response to the get request: {response:?}")
+            }
+            Some(Answer::Rekeyed) => self.send_get_request()?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn send_write(&mut self, request: ActionRequest) -> anyhow::Result<()> {
+        let session_key = self.session_key()?;
+        let encrypted_request = self.send_action(request.clone())?;
+        self.writes.push(Write {
+            request,
+            encrypted_request,
+            session_key,
+            sent_instant: Instant::now(),
+            status: WriteStatus::Pending,
+        });
+        if self.writes.len() > MAX_WRITES {
+            if let Some(index) = self
+                .writes
+                .iter()
+                .position(|write| !matches!(write.status, WriteStatus::Pending))
+            {
+                self.writes.remove(index);
+            }
+        }
+        Ok(())
+    }
+
+    fn resend_write(&mut self, index: usize) -> anyhow::Result<()> {
+        let session_key = self.session_key()?;
+        let encrypted_request = self.send_action(self.writes[index].request.clone())?;
+        let write = &mut self.writes[index];
+        write.encrypted_request = encrypted_request;
+        write.session_key = session_key;
+        write.sent_instant = Instant::now();
+        write.status = WriteStatus::Pending;
+        Ok(())
+    }
+
+    fn poll_writes(&mut self) {
+        if self
+            .writes
+            .iter()
+            .any(|write| matches!(write.status, WriteStatus::Pending))
+        {
+            self.collect_if_retry_period_elapsed();
+        }
+        for index in 0..self.writes.len() {
+            let write = &self.writes[index];
+            if !matches!(write.status, WriteStatus::Pending) {
+                continue;
+            }
+            let (encrypted_request, session_key) =
+                (write.encrypted_request.clone(), write.session_key.clone());
+            let status = match self.take_answer(&encrypted_request, &session_key) {
+                Ok(None) if self.is_queued(&encrypted_request) => {
+                    self.writes[index].sent_instant = Instant::now();
+                    continue;
+                }
+                Ok(None) if self.writes[index].sent_instant.elapsed() >= WRITE_TIMEOUT => {
+                    WriteStatus::Failed(format!("no response within {WRITE_TIMEOUT:?}"))
+                }
+                Ok(None) => continue,
+                Ok(Some(Answer::Response(ActionResponse::Error(error)))) => {
+                    WriteStatus::Failed(error.to_string())
+                }
+                Ok(Some(Answer::Response(ActionResponse::Conflict { paste, revision }))) => {
+                    if let ActionRequest::Mut {
+                        paste: local,
+                        base_revision,
+                    } = &self.writes[index].request
+                    {
+                        self.conflict = Some(Conflict {
+                            local: local.clone(),
+                            base_revision: *base_revision,
+                            current: paste,
+                            revision,
+                        });
+                    }
+                    WriteStatus::Failed(format!("conflicts with revision {revision}"))
+                }
+                Ok(Some(Answer::Response(response))) => {
+                    let request = self.writes[index].request.clone();
+                    let done = self.write_done(&request, response);
+                    self.surface(done);
+                    WriteStatus::Done
+                }
+                Ok(Some(Answer::Rekeyed)) => match self.resend_write(index) {
+                    Ok(()) => continue,
+                    Err(error) => WriteStatus::Failed(format!("{error:#}")),
+                },
+                Err(error) => WriteStatus::Failed(format!("{error:#}")),
+            };
+            // a retry must not go out next to a copy that still waits for a slot
+            if matches!(status, WriteStatus::Failed(_)) {
+                self.cancel_queued(&encrypted_request);
+            }
+            self.writes[index].status = status;
+        }
+    }
+
+    fn show_writes(&mut self, ui: &mut Ui) {
+        let mut retried = None;
+        let mut dismissed = None;
+        for (index, write) in self.writes.iter().enumerate() {
+            ui.horizontal(|ui| {
+                let description = describe_request(&write.request);
+                match &write.status {
+                    WriteStatus::Pending => {
+                        ui.add(Spinner::new());
+                        ui.label(description);
+                    }
+                    WriteStatus::Done => {
+                        if ui.button("✖").clicked() {
+                            dismissed = Some(index);
+                        }
+                        ui.colored_label(Color32::GREEN, format!("✔ {description}"));
+                    }
+                    WriteStatus::Failed(message) => {
+                        if ui.button("✖").clicked() {
+                            dismissed = Some(index);
+                        }
+                        if ui.button("Повторить").clicked() {
+                            retried = Some(index);
+                        }
+                        ui.colored_label(Color32::RED, format!("{description}: {message}"));
+                    }
+                }
+            });
+        }
+        if let Some(index) = retried {
+            let resent = self.resend_write(index);
+            self.surface(resent);
+        }
+        if let Some(index) = dismissed {
+            let write = self.writes.remove(index);
+            self.cancel_queued(&write.encrypted_request);
+        }
+    }
+
+    fn show_conflict(&mut self, ctx: &eframe::egui::Context) -> anyhow::Result<()> {
+        let Some(conflict) = &self.conflict else {
+            return Ok(());
+        };
+        let mut choice = None;
+        Window::new("Конфликт изменений")
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "Запись \"{}\" изменилась на сервере (ревизия №{}) после того, как вы начали её редактировать.",
+                    conflict.current.name, conflict.revision
+                ));
+                ui.horizontal(|ui| {
+                    if ui.button("Перезаписать").clicked() {
+                        choice = Some(ConflictChoice::Overwrite);
+                    }
+                    if ui.button("Объединить").clicked() {
+                        choice = Some(ConflictChoice::Merge);
+                    }
+                    if ui.button("Взять версию сервера").clicked() {
+                        choice = Some(ConflictChoice::TakeCurrent);
+                    }
+                    if ui.button("Отмена").clicked() {
+                        choice = Some(ConflictChoice::Cancel);
+                    }
+                });
+            });
+        let Some(choice) = choice else {
+            return Ok(());
+        };
+        let Conflict {
+            local,
+            base_revision,
+            current,
+            revision,
+        } = self.conflict.take().unwrap();
+        match choice {
+            ConflictChoice::Overwrite => self.send_write(ActionRequest::Mut {
+                paste: local,
+                base_revision: Some(revision),
+            })?,
+            ConflictChoice::Merge => self.start_merge(local, base_revision, current, revision)?,
+            ConflictChoice::TakeCurrent => {
+                let mut current = current;
+                set_text(&mut self.content, mem::take(&mut current.content));
+                self.tags = Zeroizing::new(current.tags.join(", "));
+                self.base_revision = Some((current.name.clone(), revision));
+                set_text(&mut self.name, mem::take(&mut current.name));
+            }
+            ConflictChoice::Cancel => {}
+        }
+        Ok(())
+    }
+
+    fn start_merge(
+        &mut self,
+        local: Paste,
+        base_revision: Option<u64>,
+        current: Paste,
+        revision: u64,
+    ) -> anyhow::Result<()> {
+        let mut merge = Merge {
+            local,
+            current,
+            revision,
+            hunks: None,
+            result: Zeroizing::new(String::new()),
+        };
+        match base_revision {
+            Some(base_revision) => {
+                self.pending_merge_base_request =
+                    Some(self.send_request(ActionRequest::Revision {
+                        name: merge.current.name.clone(),
+                        revision: base_revision,
+                    })?);
+            }
+            None => merge.set_base(""),
+        }
+        self.merge = Some(merge);
+        Ok(())
+    }
+
+    fn poll_merge_base_request(&mut self) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_merge_base_request.take() else {
+            return Ok(());
+        };
+        let polled = self.poll_request(pending);
+        let Some(merge) = &mut self.merge else {
+            return Ok(());
+        };
+        match polled {
+            Ok(Either::Left(ActionResponse::Paste { paste, .. })) => merge.set_base(&paste.content),
+            Ok(Either::Left(response)) => {
+                merge.set_base("");
+                anyhow::bail!("unexpected response to the revision request: {response:?}")
+            }
+            Ok(Either::Right(pending)) => self.pending_merge_base_request = Some(pending),
+            // the base revision may have dropped out of the history, merge as if
+            // both versions were written from scratch
+            Err(error) => {
+                merge.set_base("");
+                return Err(error.context("failed to fetch the base revision"));
+            }
+        }
+        Ok(())
+    }
+
+    fn show_merge(&mut self, ui: &mut Ui) {
+        let Some(mut merge) = self.merge.take() else {
+            return;
+        };
+        ui.heading(format!(
+            "Объединение \"{}\" с ревизией №{}",
+            merge.current.name, merge.revision
+        ));
+        let Some(hunks) = &mut merge.hunks else {
+            pending_label(ui, "Получаем исходную версию ...");
+            self.merge = Some(merge);
+            return;
+        };
+        let conflicts = hunks.iter().filter(|hunk| hunk.is_conflict()).count();
+        ui.label(format!("Конфликтов: {conflicts}"));
+        let mut chosen = false;
+        ScrollArea::vertical()
+            .id_source("hunks")
+            .max_height(ui.available_height() / 2.0)
+            .show(ui, |ui| {
+                for (index, hunk) in hunks.iter_mut().enumerate() {
+                    if hunk.is_unchanged() {
+                        for line in &hunk.base {
+                            ui.label(
+                                RichText::new(line.trim_end_matches(['\r', '\n']))
+                                    .color(Color32::GRAY)
+                                    .monospace(),
+                            );
+                        }
+                        continue;
+                    }
+                    ui.group(|ui| {
+                        ui.horizontal(|ui| {
+                            if hunk.is_conflict() {
+                                ui.colored_label(Color32::RED, "Конфликт");
+                            }
+                            for (choice, text) in [
+                                (Choice::Local, "Ваша"),
+                                (Choice::Remote, "Сервер"),
+                                (Choice::Both, "Обе"),
+                                (Choice::Base, "Исходная"),
+                            ] {
+                                chosen |= ui.radio_value(&mut hunk.choice, choice, text).changed();
+                            }
+                        });
+                        ui.push_id(index, |ui| {
+                            ui.columns(3, |columns| {
+                                hunk_lines(&mut columns[0], "Исходная", &hunk.base, Color32::GRAY);
+                                hunk_lines(
+                                    &mut columns[1],
+                                    "Ваша",
+                                    &hunk.local,
+                                    Color32::LIGHT_BLUE,
+                                );
+                                hunk_lines(
+                                    &mut columns[2],
+                                    "Сервер",
+                                    &hunk.remote,
+                                    Color32::LIGHT_GREEN,
+                                );
+                            });
+                        });
+                    });
+                }
+            });
+        if chosen {
+            merge.result = Zeroizing::new(merge::join(hunks));
+        }
+        let mut finished = false;
+        ui.horizontal(|ui| {
+            if ui.button("Применить").clicked() {
+                self.base_revision = Some((merge.current.name.clone(), merge.revision));
+                set_text(&mut self.name, merge.current.name.clone());
+                set_text(&mut self.content, (*merge.result).clone());
+                finished = true;
+            }
+            if ui.button("Отмена").clicked() {
+                finished = true;
+            }
+        });
+        ui.add_sized(ui.available_size(), TextEdit::multiline(&mut *merge.result));
+        if !finished {
+            self.merge = Some(merge);
+        }
+    }
+
+    fn send_attachments_request(&mut self, name: String) -> anyhow::Result<()> {
+        self.pending_attachments_request =
+            Some(self.send_request(ActionRequest::Attachments { name })?);
+        Ok(())
+    }
+
+    fn poll_attachments_request(&mut self) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_attachments_request.take() else {
+            return Ok(());
+        };
+        let request = pending.request.clone();
+        match (request, self.poll_request(pending)?) {
+            (
+                ActionRequest::Attachments { name },
+                Either::Left(ActionResponse::Attachments(attachment_infos)),
+            ) => self.attachments = Some((name, attachment_infos)),
+            (_, Either::Left(response)) => {
+                anyhow::bail!("unexpected response to the attachments request: {response:?}")
+            }
+            (_, Either::Right(pending)) => self.pending_attachments_request = Some(pending),
+        }
+        Ok(())
+    }
+
+    fn is_shared(&self, name: &str) -> bool {
+        self.paste_infos
+            .iter()
+            .any(|paste_info| paste_info.name == name && paste_info.shared.is_some())
+    }
+
+    fn send_grants_request(&mut self, name: String) -> anyhow::Result<()> {
+        self.pending_grants_request = Some(self.send_request(ActionRequest::Grants { name })?);
+        Ok(())
+    }
+
+    fn poll_grants_request(&mut self) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_grants_request.take() else {
+            return Ok(());
+        };
+        let request = pending.request.clone();
+        match (request, self.poll_request(pending)?) {
+            (ActionRequest::Grants { name }, Either::Left(ActionResponse::Grants(grants))) => {
+                self.grants = Some((name, grants))
+            }
+            (_, Either::Left(response)) => {
+                anyhow::bail!("unexpected response to the grants request: {response:?}")
+            }
+            (_, Either::Right(pending)) => self.pending_grants_request = Some(pending),
+        }
+        Ok(())
+    }
+
+    fn show_grants(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
+        let enabled = self.session_key.is_some();
+        let grants = match &self.grants {
+            Some((name, grants)) if *name == self.name => grants.clone(),
+            _ => {
+                if self.pending_grants_request.is_some() {
+                    ui.add(Spinner::new());
+                } else {
+                    ui.label("Найдите свою запись, чтобы открыть к ней доступ");
+                }
+                return Ok(());
+            }
+        };
+        let mut request = None;
+        for grant in grants {
+            ui.horizontal(|ui| {
+                if ui.add_enabled(enabled, Button::new("✖")).clicked() {
+                    request = Some(ActionRequest::Revoke {
+                        name: self.name.clone(),
+                        grantee: grant.grantee.clone(),
+                    });
+                }
+                let permission = match grant.permission {
+                    Permission::Read => "чтение",
+                    Permission::Write => "чтение и запись",
+                };
+                let key = serde_json::to_string(&grant.grantee).unwrap_or_default();
+                ui.label(format!("{}: {permission}", describe_key(&key)));
+            });
+        }
+        let mut granted = false;
+        ui.horizontal(|ui| {
+            granted = ui
+                .add_enabled(
+                    enabled && !self.grantee.trim().is_empty(),
+                    Button::new("Открыть доступ"),
+                )
+                .clicked();
+            ui.radio_value(&mut self.permission, Permission::Read, "чтение");
+            ui.radio_value(&mut self.permission, Permission::Write, "запись");
+            ui.add_sized(
+                available_width(ui, &TextStyle::Body),
+                TextEdit::singleline(&mut self.grantee).hint_text("Открытый ключ получателя"),
+            );
+        });
+        if granted {
+            let grantee: RsaPublicKey = serde_json::from_str(self.grantee.trim())?;
+            request = Some(ActionRequest::Grant {
+                name: self.name.clone(),
+                grant: Grant {
+                    grantee,
+                    permission: self.permission,
+                },
+            });
+            self.grantee.clear();
+        }
+        if let Some(request) = request {
+            self.send_write(request)?;
+        }
+        Ok(())
+    }
+
+    fn attach_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a file", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let len = fs::metadata(path)?.len();
+        if len > MAX_ATTACHMENT_LEN as u64 {
+            anyhow::bail!(
+                "{} is {len} bytes, attachments are limited to {MAX_ATTACHMENT_LEN}",
+                path.display()
+            );
+        }
+        let attachment = Attachment {
+            name,
+            mime: mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+            bytes: fs::read(path)?,
+        };
+        let mut chunks = VecDeque::from(attachment.chunks(self.rng.gen()));
+        let chunk = chunks.pop_front().unwrap();
+        // replaces an earlier upload of the attachment that stalled on a failed chunk
+        self.uploads.retain(|upload| {
+            upload.name != self.name
+                || upload
+                    .chunks
+                    .front()
+                    .is_some_and(|next| next.attachment != chunk.attachment)
+        });
+        if !chunks.is_empty() {
+            self.uploads.push(Upload {
+                name: self.name.clone(),
+                chunks,
+            });
+        }
+        self.send_write(ActionRequest::Attach {
+            name: self.name.clone(),
+            chunk,
+        })
+    }
+
+    // Sends the chunk that follows `chunk` once the server has stored it.
+    fn continue_upload(&mut self, name: &str, chunk: &AttachmentChunk) -> anyhow::Result<()> {
+        let Some(index) = self.uploads.iter().position(|upload| {
+            upload.name == name
+                && upload.chunks.front().is_some_and(|next| {
+                    next.upload == chunk.upload && next.index == chunk.index + 1
+                })
+        }) else {
+            return Ok(());
+        };
+        let next = self.uploads[index].chunks.pop_front().unwrap();
+        if self.uploads[index].chunks.is_empty() {
+            self.uploads.remove(index);
+        }
+        self.send_write(ActionRequest::Attach {
+            name: name.into(),
+            chunk: next,
+        })
+    }
+
+    fn send_download_request(
+        &mut self,
+        attachment: &AttachmentInfo,
+        index: u32,
+    ) -> anyhow::Result<PendingRequest> {
+        self.send_request(ActionRequest::Download {
+            name: self.name.clone(),
+            attachment: attachment.name.clone(),
+            index,
+        })
+    }
+
+    fn start_download(&mut self, attachment: AttachmentInfo) -> anyhow::Result<()> {
+        let path = match self.save_path.trim() {
+            // the name comes from whoever attached the file, so only its last
+            // component is used
+            "" => Path::new(&attachment.name)
+                .file_name()
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow::anyhow!("{:?} is not a file name", attachment.name))?,
+            save_path => PathBuf::from(save_path),
+        };
+        let pending = self.send_download_request(&attachment, 0)?;
+        self.download = Some(Download {
+            bytes: Vec::with_capacity(attachment.len),
+            attachment,
+            path,
+            index: 0,
+            pending,
+        });
+        Ok(())
+    }
+
+    fn poll_download(&mut self) -> anyhow::Result<()> {
+        let Some(Download {
+            attachment,
+            path,
+            mut bytes,
+            mut index,
+            pending,
+        }) = self.download.take()
+        else {
+            return Ok(());
+        };
+        let pending = match self.poll_request(pending)? {
+            Either::Left(ActionResponse::Chunk(chunk)) => {
+                // a chunk of another upload would corrupt the file
+                if chunk.upload != attachment.upload
+                    || chunk.count != attachment.count
+                    || chunk.index != index
+                {
+                    anyhow::bail!(
+                        "{} was uploaded again during the download, save it again",
+                        attachment.name
+                    );
+                }
+                bytes.extend_from_slice(&chunk.bytes);
+                if chunk.index + 1 == chunk.count {
+                    fs::write(&path, &bytes)?;
+                    self.saved_path = Some(path);
+                    return Ok(());
+                }
+                index += 1;
+                self.send_download_request(&attachment, index)?
+            }
+            Either::Left(response) => {
+                anyhow::bail!("unexpected response to the download request: {response:?}")
+            }
+            Either::Right(pending) => pending,
+        };
+        self.download = Some(Download {
+            attachment,
+            path,
+            bytes,
+            index,
+            pending,
+        });
+        Ok(())
+    }
+
+    fn show_attachments(&mut self, ui: &mut Ui) -> anyhow::Result<()> {
+        let enabled = self.session_key.is_some();
+        let attachment_infos = match &self.attachments {
+            Some((name, attachment_infos)) if *name == self.name => attachment_infos.clone(),
+            _ => {
+                if self.pending_attachments_request.is_some() {
+                    ui.add(Spinner::new());
+                } else {
+                    ui.label("Найдите запись, чтобы работать с её вложениями");
+                }
+                return Ok(());
+            }
+        };
+        let mut downloaded = None;
+        let mut detached = None;
+        for attachment in attachment_infos {
+            ui.horizontal(|ui| {
+                let downloading = self.download.is_none();
+                if ui
+                    .add_enabled(
+                        enabled && downloading && attachment.complete,
+                        Button::new("Сохранить"),
+                    )
+                    .clicked()
+                {
+                    downloaded = Some(attachment.clone());
+                }
+                if ui.add_enabled(enabled, Button::new("✖")).clicked() {
+                    detached = Some(attachment.name.clone());
+                }
+                let mut text = format!(
+                    "{} ({}, {} байт)",
+                    attachment.name, attachment.mime, attachment.len
+                );
+                if !attachment.complete {
+                    text.push_str(" — загружается");
+                }
+                ui.label(text);
+            });
+        }
+        if let Some(download) = &self.download {
+            ui.horizontal(|ui| {
+                ui.add(Spinner::new());
+                ui.label(format!(
+                    "Скачиваем \"{}\": {} из {} байт",
+                    download.attachment.name,
+                    download.bytes.len(),
+                    download.attachment.len
+                ));
+            });
+        } else if let Some(saved_path) = &self.saved_path {
+            ui.label(format!("Сохранено в {}", saved_path.display()));
+        }
+        let mut attached = false;
+        ui.horizontal(|ui| {
+            attached = ui
+                .add_enabled(
+                    enabled && !self.attach_path.trim().is_empty(),
+                    Button::new("Прикрепить файл"),
+                )
+                .clicked();
+            ui.add_sized(
+                available_width(ui, &TextStyle::Body),
+                TextEdit::singleline(&mut self.attach_path)
+                    .hint_text("Путь к файлу или перетащите файл в окно"),
+            );
+        });
+        ui.horizontal(|ui| {
+            ui.label("Сохранять как");
+            ui.add_sized(
+                available_width(ui, &TextStyle::Body),
+                TextEdit::singleline(&mut self.save_path).hint_text("Имя вложения"),
+            );
+        });
+        if attached {
+            let path = PathBuf::from(self.attach_path.trim());
+            self.attach_file(&path)?;
+            self.attach_path.clear();
+        }
+        if let Some(attachment) = downloaded {
+            self.saved_path = None;
+            self.start_download(attachment)?;
+        }
+        if let Some(attachment) = detached {
+            self.send_write(ActionRequest::Detach {
+                name: self.name.clone(),
+                attachment,
+            })?;
+        }
+        Ok(())
+    }
+
+    // Files dropped onto the window become attachments of the paste in the editor.
+    fn attach_dropped_files(&mut self, ctx: &eframe::egui::Context) -> anyhow::Result<()> {
+        let dropped_files = mem::take(&mut ctx.input_mut().raw.dropped_files);
+        if dropped_files.is_empty() {
+            return Ok(());
+        }
+        if !matches!(&self.attachments, Some((name, _)) if *name == self.name) {
+            anyhow::bail!("find the paste before attaching files to it");
+        }
+        for path in dropped_files.into_iter().filter_map(|file| file.path) {
+            self.attach_file(&path)?;
+        }
+        Ok(())
+    }
+
+    fn show_status(&mut self, ui: &mut Ui) {
+        if self.session_key.is_none() {
+            self.collect_if_retry_period_elapsed();
+            let received = self.receive_session_key();
+            self.surface(received);
+        }
+        ui.horizontal(|ui| {
+            if self.session_key.is_none() {
+                ui.add(Spinner::new());
+                ui.label("Получаем сессионный ключ ...");
+            }
+            if self.worker.in_flight() > 0 {
+                ui.add(Spinner::new());
+                ui.label(format!("Сетевых операций: {}", self.worker.in_flight()));
+            }
+        });
+        self.show_writes(ui);
+        let mut retry = None;
+        let mut dismissed = None;
+        for (index, failure) in self.failures.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.button("✖").clicked() {
+                    dismissed = Some(index);
+                }
+                if let Some(command) = &failure.retry {
+                    if ui.button("Повторить").clicked() {
+                        retry = Some(command.clone());
+                        dismissed = Some(index);
+                    }
+                }
+                ui.colored_label(Color32::RED, &failure.message);
+            });
+        }
+        if let Some(index) = dismissed {
+            self.failures.remove(index);
+        }
+        if let Some(command) = retry {
+            self.worker.send(command);
+        }
+    }
+}
+
+impl eframe::App for App {
+    fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
+        while let Some(event) = self.worker.try_recv() {
+            self.handle_event(event);
+        }
+        let polled = self.poll_cover();
+        self.surface(polled);
+        self.poll_writes();
+        let polled = self.poll_list_request();
+        self.surface(polled);
+        let polled = self.poll_search_request();
+        self.surface(polled);
+        let polled = self.poll_history_request();
+        self.surface(polled);
+        let polled = self.poll_revision_request();
+        self.surface(polled);
+        let polled = self.poll_merge_base_request();
+        self.surface(polled);
+        let polled = self.poll_attachments_request();
+        self.surface(polled);
+        let polled = self.poll_grants_request();
+        self.surface(polled);
+        let polled = self.poll_download();
+        self.surface(polled);
+        let attached = self.attach_dropped_files(ctx);
+        self.surface(attached);
+        TopBottomPanel::bottom("status").show(ctx, |ui| self.show_status(ui));
+        let shown = self.show_conflict(ctx);
+        self.surface(shown);
+        SidePanel::left("pastes").show(ctx, |ui| {
+            let shown = self.show_paste_infos(ui);
+            self.surface(shown);
+        });
+        if self.history.is_some() {
+            SidePanel::right("history").show(ctx, |ui| {
+                let shown = self.show_history(ui);
+                self.surface(shown);
+            });
+        }
+        CentralPanel::default().show(ctx, |ui| {
+            if self.merge.is_some() {
+                self.show_merge(ui);
+            } else if let (Some(pending_get_request), Some(session_key)) =
+                (self.pending_get_request.clone(), self.session_key.clone())
+            {
+                let shown = self.show_pending_get_request(ui, &pending_get_request, &session_key);
+                self.surface(shown);
+            } else {
+                let shown = self.show_new_rsa_key(ui);
+                self.surface(shown);
+                ui.group(|ui| {
+                    let shown = self.show_actions(ui);
+                    self.surface(shown);
+                    let shown = self.show_get_and_name(ui);
+                    self.surface(shown);
+                });
+                if !ctx.input().raw.hovered_files.is_empty() {
+                    ui.colored_label(
+                        Color32::LIGHT_BLUE,
+                        format!("Отпустите файлы, чтобы прикрепить их к \"{}\"", self.name),
+                    );
+                }
+                CollapsingHeader::new("Вложения").show(ui, |ui| {
+                    let shown = self.show_attachments(ui);
+                    self.surface(shown);
+                });
+                CollapsingHeader::new("Доступ").show(ui, |ui| {
+                    let shown = self.show_grants(ui);
+                    self.surface(shown);
+                });
+                ui.add_sized(ui.available_size(), TextEdit::multiline(&mut self.content));
+            }
+        });
+        ctx.request_repaint_after(PENDING_REQUEST_RETRY_PERIOD + Duration::from_secs(1));
+        if let Some(cover) = &self.cover {
+            ctx.request_repaint_after(cover.until_next_slot());
+        }
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        self.name.zeroize();
+        self.content.zeroize();
+        self.append_text.zeroize();
+        self.search_query.zeroize();
+    }
+}
+
+fn main() {
+    if let Err(error) = msg::check_settings() {
+        eprintln!("{error}");
+        process::exit(1);
+    }
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(
         "Защищённый блокнот",
         native_options,
-        Box::new(|cc| Box::new(App::new(cc).unwrap())),
+        Box::new(|cc| Box::new(App::new(cc))),
     );
 }
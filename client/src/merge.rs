@@ -0,0 +1,202 @@
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+// Line based three-way merge of two edits that share a base version. Lines
+// keep their terminators, so that merging without conflicts changes nothing.
+
+// Above this many cells of the lcs table the changed middles are not matched
+// line by line but left as a single hunk.
+const MAX_TABLE_LEN: usize = 1 << 22;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Choice {
+    Base,
+    Local,
+    Remote,
+    // local lines followed by remote lines
+    Both,
+}
+
+// Zeroed when dropped, its lines are note content.
+#[derive(Debug, Clone, Eq, PartialEq, Zeroize, ZeroizeOnDrop)]
+pub struct Hunk {
+    pub base: Vec<String>,
+    pub local: Vec<String>,
+    pub remote: Vec<String>,
+    #[zeroize(skip)]
+    pub choice: Choice,
+}
+
+impl Hunk {
+    fn new(base: &[&str], local: &[&str], remote: &[&str]) -> Self {
+        let to_strings = |lines: &[&str]| lines.iter().map(|line| line.to_string()).collect();
+        let choice = if local == base {
+            Choice::Remote
+        } else {
+            Choice::Local
+        };
+        Self {
+            base: to_strings(base),
+            local: to_strings(local),
+            remote: to_strings(remote),
+            choice,
+        }
+    }
+
+    pub fn is_unchanged(&self) -> bool {
+        self.local == self.base && self.remote == self.base
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        self.local != self.base && self.remote != self.base && self.local != self.remote
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        let (first, second): (&[String], &[String]) = match self.choice {
+            Choice::Base => (&self.base, &[]),
+            Choice::Local => (&self.local, &[]),
+            Choice::Remote => (&self.remote, &[]),
+            Choice::Both => (&self.local, &self.remote),
+        };
+        first.iter().chain(second).map(String::as_str)
+    }
+}
+
+// For every line of `a`, the index of the line of `b` it is matched with in a
+// longest common subsequence of both.
+fn matching(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a_middle = &a[prefix..a.len() - suffix];
+    let b_middle = &b[prefix..b.len() - suffix];
+
+    let mut matched = vec![None; a.len()];
+    for (index, matched) in matched.iter_mut().enumerate().take(prefix) {
+        *matched = Some(index);
+    }
+    for k in 0..suffix {
+        matched[a.len() - suffix + k] = Some(b.len() - suffix + k);
+    }
+    if (a_middle.len() + 1).saturating_mul(b_middle.len() + 1) > MAX_TABLE_LEN {
+        return matched;
+    }
+
+    // lengths[i * width + j] is the length of the lcs of a_middle[i..] and b_middle[j..]
+    let width = b_middle.len() + 1;
+    let mut lengths = vec![0u32; (a_middle.len() + 1) * width];
+    for i in (0..a_middle.len()).rev() {
+        for j in (0..b_middle.len()).rev() {
+            lengths[i * width + j] = if a_middle[i] == b_middle[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < a_middle.len() && j < b_middle.len() {
+        if a_middle[i] == b_middle[j] {
+            matched[prefix + i] = Some(prefix + j);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matched
+}
+
+fn push_hunk(hunks: &mut Vec<Hunk>, base: &[&str], local: &[&str], remote: &[&str]) {
+    if base.is_empty() && local.is_empty() && remote.is_empty() {
+        return;
+    }
+    let hunk = Hunk::new(base, local, remote);
+    match hunks.last_mut() {
+        Some(last) if last.is_unchanged() && hunk.is_unchanged() => {
+            last.base.extend_from_slice(&hunk.base);
+            last.local.extend_from_slice(&hunk.local);
+            last.remote.extend_from_slice(&hunk.remote);
+        }
+        _ => hunks.push(hunk),
+    }
+}
+
+// Splits the three versions into hunks. Hunks changed on one side only, or the
+// same way on both, already choose that change; conflicting hunks choose local.
+pub fn merge(base: &str, local: &str, remote: &str) -> Vec<Hunk> {
+    let base: Vec<_> = base.split_inclusive('\n').collect();
+    let local: Vec<_> = local.split_inclusive('\n').collect();
+    let remote: Vec<_> = remote.split_inclusive('\n').collect();
+    let to_local = matching(&base, &local);
+    let to_remote = matching(&base, &remote);
+
+    let mut hunks = Vec::new();
+    let (mut b, mut l, mut r) = (0, 0, 0);
+    for (i, matched) in to_local.into_iter().zip(to_remote).enumerate() {
+        let (Some(lm), Some(rm)) = matched else {
+            continue;
+        };
+        push_hunk(&mut hunks, &base[b..i], &local[l..lm], &remote[r..rm]);
+        push_hunk(&mut hunks, &base[i..=i], &local[lm..=lm], &remote[rm..=rm]);
+        (b, l, r) = (i + 1, lm + 1, rm + 1);
+    }
+    push_hunk(&mut hunks, &base[b..], &local[l..], &remote[r..]);
+    hunks
+}
+
+pub fn join(hunks: &[Hunk]) -> String {
+    let mut text = String::new();
+    for line in hunks.iter().flat_map(Hunk::lines) {
+        // only the last line of a version lacks a terminator, but `Both` may
+        // put lines after it
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(line);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merge::{join, merge};
+
+    #[test]
+    fn merges_edits_of_different_lines() {
+        let hunks = merge("a\nb\nc\nd", "a\nB\nc\nd", "a\nb\nc\nD");
+        assert!(hunks.iter().all(|hunk| !hunk.is_conflict()));
+        assert_eq!(join(&hunks), "a\nB\nc\nD");
+    }
+
+    #[test]
+    fn reports_edits_of_the_same_line_as_conflict() {
+        let hunks = merge("a\nb\nc", "a\nlocal\nc", "a\nremote\nc");
+        assert_eq!(hunks.iter().filter(|hunk| hunk.is_conflict()).count(), 1);
+        assert_eq!(join(&hunks), "a\nlocal\nc");
+    }
+
+    #[test]
+    fn keeps_line_terminators() {
+        let text = "a\r\nb\n\nc\n";
+        let hunks = merge(text, text, "a\r\nb\n\nc\nd\n");
+        assert_eq!(join(&hunks), "a\r\nb\n\nc\nd\n");
+        assert_eq!(join(&merge(text, text, text)), text);
+    }
+
+    #[test]
+    fn leaves_large_changes_as_one_hunk() {
+        let base: String = (0..3000).map(|i| format!("{i}\n")).collect();
+        let local = format!("first\n{}last\n", &base[2..base.len() - 5]);
+        let remote = base.replacen("1500\n", "middle\n", 1);
+        let hunks = merge(&base, &local, &remote);
+        assert_eq!(hunks.len(), 1);
+        assert!(hunks[0].is_conflict());
+    }
+}
@@ -0,0 +1,28 @@
+// Pastes grouped into the folders their names consist of.
+
+use std::collections::BTreeMap;
+
+use msg::PasteInfo;
+
+#[derive(Debug, Clone, Default)]
+pub struct Folder {
+    pub folders: BTreeMap<String, Folder>,
+    // (last part of the name, paste) in the order of the listing
+    pub pastes: Vec<(String, PasteInfo)>,
+}
+
+impl Folder {
+    pub fn new(paste_infos: &[PasteInfo]) -> Self {
+        let mut root = Self::default();
+        for paste_info in paste_infos {
+            let mut parts: Vec<_> = paste_info.name.split('/').collect();
+            let last = parts.pop().unwrap_or_default();
+            let mut folder = &mut root;
+            for part in parts {
+                folder = folder.folders.entry(part.into()).or_default();
+            }
+            folder.pastes.push((last.into(), paste_info.clone()));
+        }
+        root
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::HashSet,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use eframe::egui::Context;
+use gist::GistId;
+use msg::Msg;
+use tokio::{
+    runtime::Builder,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+};
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    // skips the gists listed
+    Collect(HashSet<GistId>),
+    Insert(Box<Msg>),
+    Remove(GistId),
+}
+
+#[derive(Debug)]
+pub enum Event {
+    // a gist that fails to download or decode only fails its own entry
+    Collected(anyhow::Result<Vec<(GistId, anyhow::Result<Msg>)>>),
+    Inserted(Msg, anyhow::Result<GistId>),
+    Removed(GistId, anyhow::Result<()>),
+    Pushed(GistId, Msg),
+    Stopped(anyhow::Error),
+}
+
+impl Event {
+    fn answers_command(&self) -> bool {
+        !matches!(self, Self::Pushed(..) | Self::Stopped(..))
+    }
+}
+
+// Runs every gist call on a background tokio runtime. Commands are answered by
+// events in completion order, and each event wakes up the ui.
+pub struct Worker {
+    commands: UnboundedSender<Command>,
+    events: Receiver<Event>,
+    in_flight: usize,
+}
+
+fn send_event(events: &Sender<Event>, ctx: &Context, event: Event) {
+    if events.send(event).is_ok() {
+        ctx.request_repaint();
+    }
+}
+
+async fn run(command: Command) -> Event {
+    match command {
+        Command::Collect(skip) => Event::Collected(gist::nonblocking::collect_each(&skip).await),
+        Command::Insert(msg) => {
+            let gist_id = gist::nonblocking::insert((*msg).clone()).await;
+            Event::Inserted(*msg, gist_id)
+        }
+        Command::Remove(gist_id) => {
+            let removed = gist::nonblocking::remove(gist_id.clone()).await;
+            Event::Removed(gist_id, removed)
+        }
+    }
+}
+
+impl Worker {
+    pub fn spawn(ctx: Context) -> Self {
+        let (commands, mut command_receiver) = unbounded_channel();
+        let (event_sender, events) = mpsc::channel();
+
+        let push_event_sender = event_sender.clone();
+        let push_ctx = ctx.clone();
+        push::subscribe(push::addr(), move |gist_id, msg| {
+            send_event(&push_event_sender, &push_ctx, Event::Pushed(gist_id, msg));
+        });
+
+        thread::spawn(move || {
+            let runtime = match Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(error) => return send_event(&event_sender, &ctx, Event::Stopped(error.into())),
+            };
+            runtime.block_on(async move {
+                while let Some(command) = command_receiver.recv().await {
+                    let event_sender = event_sender.clone();
+                    let ctx = ctx.clone();
+                    tokio::spawn(async move {
+                        send_event(&event_sender, &ctx, run(command).await);
+                    });
+                }
+            })
+        });
+
+        Self {
+            commands,
+            events,
+            in_flight: 0,
+        }
+    }
+
+    pub fn send(&mut self, command: Command) {
+        if self.commands.send(command).is_ok() {
+            self.in_flight += 1;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Event> {
+        let event = self.events.try_recv().ok()?;
+        if event.answers_command() {
+            self.in_flight = self.in_flight.saturating_sub(1);
+        }
+        Some(event)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
@@ -4,6 +4,8 @@ use curl::easy::{Easy, List};
 use json::{object::Object as JsonObject, JsonValue};
 use msg::Msg;
 
+pub mod nonblocking;
+
 pub type GistId = String;
 
 pub fn recv(handle: &mut Easy, capacity: usize) -> anyhow::Result<String> {
@@ -64,47 +66,54 @@ fn json_object_field<'a>(
 }
 
 fn gist_id(gist_info_value: &JsonValue) -> anyhow::Result<GistId> {
-    let gist_info_object = json_object(&gist_info_value)?;
-    let gist_id = json_object_field(&gist_info_object, "id")?
+    let gist_info_object = json_object(gist_info_value)?;
+    let gist_id = json_object_field(gist_info_object, "id")?
         .as_str()
         .ok_or_else(|| anyhow::anyhow!("expected gist info to contain id string"))?;
     Ok(gist_id.into())
 }
 
-pub fn collect() -> anyhow::Result<Vec<(GistId, Msg)>> {
-    let mut output = Vec::with_capacity(256);
-
+pub fn list() -> anyhow::Result<Vec<GistId>> {
     let mut handle = handle("https://api.github.com/gists")?;
     handle.get(true)?;
     let gists_value = json::parse(&recv(&mut handle, 32728)?)?;
     let gists_array = json_array(&gists_value)?;
-    for gist_info in gists_array {
-        let gist_id = gist_id(&gist_info)?;
-        handle.url(&format!("https://api.github.com/gists/{gist_id}"))?;
-        let gist_value = json::parse(&recv(&mut handle, 16384)?)?;
-        let gist_object = json_object(&gist_value)?;
-        let files_value = json_object_field(&gist_object, "files")?;
-        let files_object = json_object(&files_value)?;
-        if let Some((_, file)) = files_object
-            .iter()
-            .find(|(file_name, _)| *file_name == "msg.json")
-        {
-            let file_object = json_object(file)?;
-            let file_content = json_object_field(file_object, "content")?
-                .as_str()
-                .ok_or_else(|| anyhow::anyhow!("expected file msg.json to have string content"))?;
-            let msg = serde_json::from_str(file_content)?;
-            output.push((gist_id.into(), msg));
-        }
+    gists_array.iter().map(gist_id).collect()
+}
+
+pub fn fetch(gist_id: &str) -> anyhow::Result<Option<Msg>> {
+    let mut handle = handle(&format!("https://api.github.com/gists/{gist_id}"))?;
+    handle.get(true)?;
+    let gist_value = json::parse(&recv(&mut handle, 16384)?)?;
+    let gist_object = json_object(&gist_value)?;
+    let files_value = json_object_field(gist_object, "files")?;
+    let files_object = json_object(files_value)?;
+    if let Some((_, file)) = files_object
+        .iter()
+        .find(|(file_name, _)| *file_name == "msg.json")
+    {
+        let file_object = json_object(file)?;
+        let file_content = json_object_field(file_object, "content")?
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("expected file msg.json to have string content"))?;
+        Ok(Some(serde_json::from_str(file_content)?))
+    } else {
+        Ok(None)
     }
+}
 
+pub fn collect() -> anyhow::Result<Vec<(GistId, Msg)>> {
+    let mut output = Vec::with_capacity(256);
+    for gist_id in list()? {
+        if let Some(msg) = fetch(&gist_id)? {
+            output.push((gist_id, msg));
+        }
+    }
     Ok(output)
 }
 
 pub fn insert(msg: &Msg) -> anyhow::Result<GistId> {
-    let msg_json_string = serde_json::to_string_pretty(msg)?
-        .replace('\n', "\\n")
-        .replace('\"', "\\\"");
+    let msg_json_string = serde_json::to_string(msg)?.replace('\"', "\\\"");
     let data =
         format!("{{ \"description\": \"Safe Notepad Msg\", \"public\": true, \"files\": {{\"msg.json\": {{ \"content\": \"{msg_json_string}\" }} }} }}");
     std::fs::write("data.json", &data)?;
@@ -124,7 +133,7 @@ pub fn insert(msg: &Msg) -> anyhow::Result<GistId> {
     handle.post(true)?;
     let gist_info_value = json::parse(&recv(&mut handle, 8192)?)?;
 
-    Ok(gist_id(&gist_info_value)?)
+    gist_id(&gist_info_value)
 }
 
 pub fn remove(gist_id: &str) -> anyhow::Result<()> {
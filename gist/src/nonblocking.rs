@@ -0,0 +1,106 @@
+// Async counterparts of the blocking gist api. Every http call still goes
+// through curl, but runs on tokio's blocking pool so that many calls proceed
+// concurrently and callers stay free to drive a ui or other tasks.
+
+use std::collections::HashSet;
+
+use msg::Msg;
+use tokio::task::{self, JoinHandle};
+
+use crate::GistId;
+
+async fn join<T>(handles: Vec<JoinHandle<anyhow::Result<T>>>) -> anyhow::Result<Vec<T>> {
+    let mut output = Vec::with_capacity(handles.len());
+    for handle in handles {
+        output.push(handle.await??);
+    }
+    Ok(output)
+}
+
+pub async fn list() -> anyhow::Result<Vec<GistId>> {
+    task::spawn_blocking(crate::list).await?
+}
+
+pub async fn fetch(gist_id: GistId) -> anyhow::Result<Option<Msg>> {
+    task::spawn_blocking(move || crate::fetch(&gist_id)).await?
+}
+
+pub async fn collect() -> anyhow::Result<Vec<(GistId, Msg)>> {
+    let handles = list()
+        .await?
+        .into_iter()
+        .map(|gist_id| {
+            task::spawn_blocking(move || {
+                crate::fetch(&gist_id).map(|msg| msg.map(|msg| (gist_id, msg)))
+            })
+        })
+        .collect();
+    Ok(join(handles).await?.into_iter().flatten().collect())
+}
+
+// Like collect, but a gist that fails to download or decode only fails its own
+// entry. Decoding failures can be told apart with `error.is::<serde_json::Error>()`.
+// Gists in `skip` are not downloaded at all.
+pub async fn collect_each(
+    skip: &HashSet<GistId>,
+) -> anyhow::Result<Vec<(GistId, anyhow::Result<Msg>)>> {
+    let handles = list()
+        .await?
+        .into_iter()
+        .filter(|gist_id| !skip.contains(gist_id))
+        .map(|gist_id| task::spawn_blocking(move || (crate::fetch(&gist_id), gist_id)))
+        .collect::<Vec<_>>();
+    let mut output = Vec::with_capacity(handles.len());
+    for handle in handles {
+        match handle.await? {
+            (Ok(Some(msg)), gist_id) => output.push((gist_id, Ok(msg))),
+            (Ok(None), _) => {}
+            (Err(error), gist_id) => output.push((gist_id, Err(error))),
+        }
+    }
+    Ok(output)
+}
+
+pub async fn insert(msg: Msg) -> anyhow::Result<GistId> {
+    task::spawn_blocking(move || crate::insert(&msg)).await?
+}
+
+pub async fn remove(gist_id: GistId) -> anyhow::Result<()> {
+    task::spawn_blocking(move || crate::remove(&gist_id)).await?
+}
+
+pub async fn insert_all(msgs: Vec<Msg>) -> anyhow::Result<Vec<(Msg, anyhow::Result<GistId>)>> {
+    let handles = msgs
+        .into_iter()
+        .map(|msg| {
+            task::spawn_blocking(move || {
+                let gist_id = crate::insert(&msg);
+                (msg, gist_id)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut output = Vec::with_capacity(handles.len());
+    for handle in handles {
+        output.push(handle.await?);
+    }
+    Ok(output)
+}
+
+pub async fn remove_all(
+    gist_ids: Vec<GistId>,
+) -> anyhow::Result<Vec<(GistId, anyhow::Result<()>)>> {
+    let handles = gist_ids
+        .into_iter()
+        .map(|gist_id| {
+            task::spawn_blocking(move || {
+                let removed = crate::remove(&gist_id);
+                (gist_id, removed)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut output = Vec::with_capacity(handles.len());
+    for handle in handles {
+        output.push(handle.await?);
+    }
+    Ok(output)
+}
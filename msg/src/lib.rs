@@ -1,18 +1,182 @@
 use either::Either;
+use std::{
+    cmp::Ordering,
+    env, fmt,
+    io::{Read, Write},
+    ops::Deref,
+    str::FromStr,
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
 pub use rsa::{RsaPrivateKey, RsaPublicKey};
 
 use aes::{
-    cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit},
+    cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit, KeyIvInit},
     Aes256,
 };
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use generic_array::GenericArray;
-use rand::{CryptoRng, RngCore};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, Rng, RngCore};
 use rsa::{errors::Result as RsaResult, PaddingScheme, PublicKey};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_encrypt::{serialize::impls::BincodeSerializer, traits::SerdeEncryptSharedKey};
+use sha2::Sha256;
 use typenum::consts::U16;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+// Zeroed when dropped, and so is every clone of it, which is why it is not Copy.
+#[derive(Clone, Eq, PartialEq)]
+pub struct AesKey(aes::cipher::Key<Aes256>);
+
+impl AesKey {
+    pub fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        let mut key = Self(Default::default());
+        rng.fill_bytes(&mut key.0);
+        key
+    }
+}
+
+impl Deref for AesKey {
+    type Target = aes::cipher::Key<Aes256>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for AesKey {
+    fn drop(&mut self) {
+        self.0.as_mut_slice().zeroize();
+    }
+}
+
+impl fmt::Debug for AesKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AesKey(..)")
+    }
+}
+
+// Attachments travel in chunks of at most this many bytes, so that every
+// request and response stays well within the gist file size limit.
+pub const ATTACHMENT_CHUNK_LEN: usize = 64 * 1024;
+
+pub const MAX_ATTACHMENT_LEN: usize = 16 * 1024 * 1024;
+
+// Shorter plaintexts rarely get smaller when deflated.
+const MIN_COMPRESSED_LEN: usize = 64;
+
+// A few bytes of deflate stream can inflate to gigabytes, so inflating stops here.
+const MAX_INFLATED_LEN: u64 = 16 * 1024 * 1024;
+
+pub const PADDING_VAR: &str = "SAFE_NOTEPAD_PADDING";
+
+// A larger step would only waste space, no paste is that long.
+pub const MAX_PADDING_STEP: usize = 256 * 1024;
+
+// How plaintexts are padded before encryption, so that the length of the
+// ciphertext only tells which bucket the plaintext falls into.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum Padding {
+    #[default]
+    None,
+    // up to the next power of two
+    PowerOfTwo,
+    // up to the next multiple of the step in bytes
+    Steps(usize),
+}
+
+impl Padding {
+    pub fn padded_len(self, len: usize) -> usize {
+        let padded_len = match self {
+            Padding::None => return len,
+            Padding::PowerOfTwo => len.next_power_of_two(),
+            Padding::Steps(step) => len.div_ceil(step) * step,
+        };
+        // whole blocks, so that the length of the last one tells nothing either
+        padded_len.max(1).next_multiple_of(16)
+    }
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" | "none" => Ok(Padding::None),
+            "pow2" => Ok(Padding::PowerOfTwo),
+            step => match step.parse() {
+                Ok(step) if (1..=MAX_PADDING_STEP).contains(&step) => Ok(Padding::Steps(step)),
+                _ => Err(format!(
+                    "expected none, pow2 or a step of 1 to {MAX_PADDING_STEP} bytes, found {step:?}"
+                )),
+            },
+        }
+    }
+}
+
+static PADDING: OnceLock<Result<Padding, String>> = OnceLock::new();
+
+fn parse_padding() -> Result<Padding, String> {
+    match env::var(PADDING_VAR) {
+        Ok(value) => value
+            .parse()
+            .map_err(|error| format!("invalid {PADDING_VAR}: {error}")),
+        Err(_) => Ok(Padding::default()),
+    }
+}
+
+// The padding policy of this deployment, set through PADDING_VAR.
+pub fn padding() -> Padding {
+    PADDING
+        .get_or_init(parse_padding)
+        .clone()
+        .unwrap_or_else(|error| panic!("{error}"))
+}
+
+pub const COVER_VAR: &str = "SAFE_NOTEPAD_COVER";
 
-pub type AesKey = aes::cipher::Key<Aes256>;
+static COVER_PERIOD: OnceLock<Result<Option<Duration>, String>> = OnceLock::new();
+
+fn parse_cover_period() -> Result<Option<Duration>, String> {
+    let Ok(value) = env::var(COVER_VAR) else {
+        return Ok(None);
+    };
+    match value.trim().parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Some(Duration::from_secs_f64(secs))),
+        _ => Err(format!(
+            "invalid {COVER_VAR}: expected a number of seconds, found {value:?}"
+        )),
+    }
+}
+
+// The mean interval in seconds between the slots of cover traffic, set through
+// COVER_VAR. Cover traffic is off unless it is set.
+pub fn cover_period() -> Option<Duration> {
+    COVER_PERIOD
+        .get_or_init(parse_cover_period)
+        .clone()
+        .unwrap_or_else(|error| panic!("{error}"))
+}
+
+// Reads the settings of this deployment from the environment, so that an
+// invalid value is reported at startup rather than in the middle of an action.
+pub fn check_settings() -> Result<(), String> {
+    let padding = PADDING.get_or_init(parse_padding).clone()?;
+    let cover_period = COVER_PERIOD.get_or_init(parse_cover_period).clone()?;
+    // sealed requests hide the kind of action but not its size
+    if cover_period.is_some() && padding == Padding::None {
+        return Err(format!("{COVER_VAR} requires {PADDING_VAR} to be set"));
+    }
+    Ok(())
+}
+
+// A random interval until the next slot, from half to one and a half periods.
+pub fn cover_slot_interval<R: Rng>(rng: &mut R, period: Duration) -> Duration {
+    period.mul_f64(rng.gen_range(0.5..1.5))
+}
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct GreetRequest(pub RsaPublicKey);
@@ -39,51 +203,310 @@ pub fn decrypt_aes_key(
 ) -> RsaResult<AesKey> {
     rsa_private_key
         .decrypt(PaddingScheme::new_pkcs1v15_encrypt(), aes_key)
-        .map(|bytes| GenericArray::from_slice(&bytes).clone())
+        .map(|bytes| AesKey(*GenericArray::from_slice(&Zeroizing::new(bytes))))
+}
+
+// Ciphertext blocks as one base64 string in human readable formats such as the
+// JSON in gists and as bytes otherwise. Still reads the older format of one
+// number per byte.
+mod blocks {
+    use std::fmt;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use generic_array::GenericArray;
+    use serde::{
+        de::{self, SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+    use typenum::consts::U16;
+
+    type Block = GenericArray<u8, U16>;
+
+    fn to_blocks<E: de::Error>(bytes: &[u8]) -> Result<Vec<Block>, E> {
+        if !bytes.len().is_multiple_of(16) {
+            return Err(E::invalid_length(bytes.len(), &"a multiple of 16 bytes"));
+        }
+        Ok(bytes
+            .chunks(16)
+            .map(|chunk| *GenericArray::from_slice(chunk))
+            .collect())
+    }
+
+    pub fn serialize<S: Serializer>(blocks: &[Block], serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes: Vec<u8> = blocks.iter().flatten().copied().collect();
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(&bytes)
+        }
+    }
+
+    struct BlocksVisitor;
+
+    impl<'de> Visitor<'de> for BlocksVisitor {
+        type Value = Vec<Block>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("base64, bytes or a sequence of 16 byte blocks")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            to_blocks(&STANDARD.decode(v).map_err(E::custom)?)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            to_blocks(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut blocks = Vec::new();
+            while let Some(block) = seq.next_element()? {
+                blocks.push(block);
+            }
+            Ok(blocks)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Block>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(BlocksVisitor)
+        } else {
+            deserializer.deserialize_bytes(BlocksVisitor)
+        }
+    }
+}
+
+// The initialization vector in the same format as the blocks, empty for data
+// without one.
+mod iv {
+    use generic_array::GenericArray;
+    use serde::{de, Deserializer, Serializer};
+    use typenum::consts::U16;
+
+    type Block = GenericArray<u8, U16>;
+
+    pub fn serialize<S: Serializer>(iv: &Option<Block>, serializer: S) -> Result<S::Ok, S::Error> {
+        super::blocks::serialize(iv.as_slice(), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Block>, D::Error> {
+        match super::blocks::deserialize(deserializer)?[..] {
+            [] => Ok(None),
+            [iv] => Ok(Some(iv)),
+            ref blocks => Err(de::Error::invalid_length(
+                blocks.len(),
+                &"at most one block",
+            )),
+        }
+    }
+}
+
+// The MAC in the same format as the blocks, empty for data without one.
+mod mac {
+    use generic_array::GenericArray;
+    use serde::{de, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        mac: &Option<[u8; 32]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let blocks: Vec<_> = mac
+            .iter()
+            .flat_map(|mac| mac.chunks(16))
+            .map(|chunk| *GenericArray::from_slice(chunk))
+            .collect();
+        super::blocks::serialize(&blocks, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 32]>, D::Error> {
+        let blocks = super::blocks::deserialize(deserializer)?;
+        match blocks[..] {
+            [] => Ok(None),
+            [first, second] => {
+                let mut mac = [0; 32];
+                mac[..16].copy_from_slice(&first);
+                mac[16..].copy_from_slice(&second);
+                Ok(Some(mac))
+            }
+            _ => Err(de::Error::invalid_length(blocks.len(), &"no or two blocks")),
+        }
+    }
+}
+
+// An HMAC keyed with a key derived from `key` for the purpose `label`, so that
+// the AES key itself is never used as a MAC key.
+fn keyed_mac(key: &AesKey, label: &[u8]) -> Hmac<Sha256> {
+    let mut mac_key = Zeroizing::new([0; 32]);
+    Hkdf::<Sha256>::new(None, key)
+        .expand(label, &mut *mac_key)
+        .unwrap();
+    <Hmac<Sha256> as Mac>::new_from_slice(&*mac_key).unwrap()
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct EncryptedData {
+    // random for every encryption so that equal plaintexts encrypt differently,
+    // None for data that older versions encrypted block by block
+    #[serde(default, with = "iv")]
+    pub iv: Option<GenericArray<u8, U16>>,
+    #[serde(with = "blocks")]
     pub blocks: Vec<GenericArray<u8, U16>>,
     pub last_block_len: usize,
+    // whether the plaintext was deflated before encryption
+    #[serde(default)]
+    pub compressed: bool,
+    // HMAC of all of the above, so that data under another key or altered data
+    // is rejected rather than parsed. None only for data without an IV.
+    #[serde(default, with = "mac")]
+    pub mac: Option<[u8; 32]>,
+}
+
+fn deflate(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes).ok()?;
+    encoder.finish().ok()
 }
 
 impl EncryptedData {
     pub fn encrypt<T: Serialize>(x: &T, key: &AesKey) -> serde_cbor::Result<Self> {
-        let bytes = serde_cbor::to_vec(x)?;
+        Self::encrypt_padded(x, key, Padding::None)
+    }
+
+    // Only values whose length tells something, such as paste content, are
+    // worth padding. Padding every field would multiply the size of a request.
+    pub fn encrypt_padded<T: Serialize>(
+        x: &T,
+        key: &AesKey,
+        padding: Padding,
+    ) -> serde_cbor::Result<Self> {
+        let mut bytes = Zeroizing::new(serde_cbor::to_vec(x)?);
+        let mut compressed = false;
+        if bytes.len() >= MIN_COMPRESSED_LEN {
+            if let Some(deflated) = deflate(&bytes).filter(|deflated| deflated.len() < bytes.len())
+            {
+                bytes = Zeroizing::new(deflated);
+                compressed = true;
+            }
+        }
+        // zeros after the end of the CBOR or deflate stream are never read
+        let padded_len = padding.padded_len(bytes.len());
+        bytes.resize(padded_len, 0);
+        Ok(Self::encrypt_bytes(&bytes, key, compressed))
+    }
+
+    fn encrypt_bytes(bytes: &[u8], key: &AesKey, compressed: bool) -> Self {
         let mut last_block_len = 16;
         let mut blocks: Vec<_> = bytes
             .chunks(16)
             .map(|chunk| {
                 if chunk.len() == 16 {
-                    GenericArray::from_slice(chunk).clone()
+                    *GenericArray::from_slice(chunk)
                 } else {
                     let mut array = [0; 16];
                     last_block_len = chunk.len();
                     array[0..chunk.len()].copy_from_slice(chunk);
-                    GenericArray::from_slice(&array).clone()
+                    *GenericArray::from_slice(&array)
                 }
             })
             .collect();
-        let mut cipher = Aes256::new(key);
-        cipher.encrypt_blocks_mut(&mut blocks);
-        Ok(Self {
+        let iv = GenericArray::from(rand::random::<[u8; 16]>());
+        cbc::Encryptor::<Aes256>::new(key, &iv).encrypt_blocks_mut(&mut blocks);
+        let mut data = Self {
+            iv: Some(iv),
             blocks,
             last_block_len,
-        })
+            compressed,
+            mac: None,
+        };
+        data.mac = Some(data.hmac(key).finalize().into_bytes().into());
+        data
+    }
+
+    fn hmac(&self, key: &AesKey) -> Hmac<Sha256> {
+        let mut mac = keyed_mac(key, b"safe_notepad encrypted data");
+        if let Some(iv) = &self.iv {
+            mac.update(iv);
+        }
+        for block in &self.blocks {
+            mac.update(block);
+        }
+        mac.update(&(self.last_block_len as u64).to_be_bytes());
+        mac.update(&[self.compressed as u8]);
+        mac
+    }
+
+    pub fn decrypt<T: for<'de> Deserialize<'de>>(self, key: &AesKey) -> serde_cbor::Result<T> {
+        let (compressed, padded) = (self.compressed, self.mac.is_some());
+        let bytes = self.decrypt_bytes(key)?;
+        if compressed {
+            T::deserialize(&mut serde_cbor::Deserializer::from_reader(
+                DeflateDecoder::new(&bytes[..]).take(MAX_INFLATED_LEN),
+            ))
+        } else if padded {
+            T::deserialize(&mut serde_cbor::Deserializer::from_slice(&bytes))
+        } else {
+            // older data is never padded, so it must end with the value
+            serde_cbor::from_slice(&bytes)
+        }
     }
 
-    pub fn decrypt<T: for<'de> Deserialize<'de>>(mut self, key: &AesKey) -> serde_cbor::Result<T> {
-        let mut cipher = Aes256::new(key);
-        cipher.decrypt_blocks_mut(&mut self.blocks);
-        let mut bytes = Vec::with_capacity(self.blocks.len() * 16);
+    // The padded CBOR or deflate stream.
+    fn decrypt_bytes(mut self, key: &AesKey) -> serde_cbor::Result<Zeroizing<Vec<u8>>> {
+        // anyone can post data, so the length is checked before it is used
+        if !self.blocks.is_empty() && !(1..=16).contains(&self.last_block_len) {
+            return Err(de::Error::invalid_value(
+                de::Unexpected::Unsigned(self.last_block_len as u64),
+                &"a last block length from 1 to 16",
+            ));
+        }
+        match (&self.iv, &self.mac) {
+            (Some(_), Some(mac)) => self.hmac(key).verify_slice(mac).map_err(|_| {
+                <serde_cbor::Error as de::Error>::custom("data is altered or under another key")
+            })?,
+            (None, None) => {}
+            _ => {
+                return Err(de::Error::custom(
+                    "data has an IV without a MAC or the reverse",
+                ))
+            }
+        }
+        match self.iv {
+            Some(iv) => {
+                cbc::Decryptor::<Aes256>::new(key, &iv).decrypt_blocks_mut(&mut self.blocks)
+            }
+            None => Aes256::new(key).decrypt_blocks_mut(&mut self.blocks),
+        }
+        let mut bytes = Zeroizing::new(Vec::with_capacity(self.blocks.len() * 16));
         for chunk in &self.blocks[..self.blocks.len().saturating_sub(1)] {
             bytes.extend_from_slice(chunk);
         }
         if let Some(last) = self.blocks.last() {
             bytes.extend_from_slice(&last[..self.last_block_len]);
         }
-        serde_cbor::from_slice(&bytes)
+        for block in &mut self.blocks {
+            block.as_mut_slice().zeroize();
+        }
+        Ok(bytes)
+    }
+
+    // Re-encrypts the data under another key without knowing its type,
+    // keeping its compression and padding.
+    pub fn rekey(self, key: &AesKey, new_key: &AesKey) -> serde_cbor::Result<Self> {
+        let compressed = self.compressed;
+        Ok(Self::encrypt_bytes(
+            &self.decrypt_bytes(key)?,
+            new_key,
+            compressed,
+        ))
+    }
+
+    // The length after compression and padding.
+    pub fn plaintext_len(&self) -> usize {
+        (self.blocks.len() * 16).saturating_sub(16 - self.last_block_len.min(16))
     }
 }
 
@@ -91,25 +514,202 @@ impl SerdeEncryptSharedKey for EncryptedData {
     type S = BincodeSerializer<Self>;
 }
 
+// A keyed hash of a name that the server looks pastes and attachments up by.
+// Equal names give equal indexes under one key, but the index tells nothing
+// else about the name. The hash key is derived from the AES key rather than
+// being the AES key itself.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct BlindIndex([u8; 32]);
+
+impl BlindIndex {
+    pub fn new(name: &str, key: &AesKey) -> Self {
+        let mut mac = keyed_mac(key, b"safe_notepad blind index");
+        mac.update(name.as_bytes());
+        Self(mac.finalize().into_bytes().into())
+    }
+}
+
+impl Serialize for BlindIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let blocks: Vec<_> = self
+            .0
+            .chunks(16)
+            .map(|chunk| *GenericArray::from_slice(chunk))
+            .collect();
+        blocks::serialize(&blocks, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlindIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: Vec<u8> = blocks::deserialize(deserializer)?
+            .into_iter()
+            .flatten()
+            .collect();
+        let len = bytes.len();
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| de::Error::invalid_length(len, &"32 bytes"))
+    }
+}
+
+// A name encrypted with a random IV together with its blind index.
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct EncryptedName {
+    pub index: BlindIndex,
+    pub name: EncryptedData,
+}
+
+impl EncryptedName {
+    pub fn encrypt(name: &str, key: &AesKey) -> serde_cbor::Result<Self> {
+        Ok(Self {
+            index: BlindIndex::new(name, key),
+            name: EncryptedData::encrypt(&name, key)?,
+        })
+    }
+
+    pub fn decrypt(self, key: &AesKey) -> serde_cbor::Result<String> {
+        self.name.decrypt(key)
+    }
+
+    // Whether the index is the one of the encrypted name. Nothing else ties
+    // the two together, so the server checks this before it trusts an index.
+    pub fn is_bound(&self, key: &AesKey) -> serde_cbor::Result<bool> {
+        Ok(BlindIndex::new(&self.name.clone().decrypt::<String>(key)?, key) == self.index)
+    }
+}
+
+// Names are paths like "work/servers/db", with folders separated by "/".
+pub fn in_folder(name: &str, folder: &str) -> bool {
+    let folder = folder.trim_matches('/');
+    folder.is_empty()
+        || name
+            .strip_prefix(folder)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .is_some()
+}
+
+// The name a paste gets when the folder `from` is moved into `to`, None if it
+// is not in that folder.
+pub fn moved_name(name: &str, from: &str, to: &str) -> Option<String> {
+    let (from, to) = (from.trim_matches('/'), to.trim_matches('/'));
+    if from.is_empty() {
+        return None;
+    }
+    let rest = name.strip_prefix(from)?.strip_prefix('/')?;
+    Some(if to.is_empty() {
+        rest.into()
+    } else {
+        format!("{to}/{rest}")
+    })
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct Paste {
     pub name: String,
     pub content: String,
+    pub tags: Vec<String>,
 }
 
 impl Paste {
     pub fn encrypt(&self, key: &AesKey) -> serde_cbor::Result<EncryptedPaste> {
         Ok(EncryptedPaste {
-            name: EncryptedData::encrypt(&self.name, key)?,
-            content: EncryptedData::encrypt(&self.content, key)?,
+            name: EncryptedName::encrypt(&self.name, key)?,
+            content: EncryptedData::encrypt_padded(&self.content, key, padding())?,
+            tags: EncryptedData::encrypt(&self.tags, key)?,
         })
     }
 }
 
+// Limits after which the server deletes a paste, none of them by default.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Expiry {
+    pub expires_at: Option<SystemTime>,
+    // how many times the content can be read before the paste is deleted
+    pub max_reads: Option<u32>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>,
+}
+
+impl Attachment {
+    // `upload` tells the chunks of this upload apart from those of an earlier
+    // upload of an attachment with the same name.
+    pub fn chunks(&self, upload: u64) -> Vec<AttachmentChunk> {
+        let mut chunks: Vec<_> = self.bytes.chunks(ATTACHMENT_CHUNK_LEN).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let count = chunks.len() as u32;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, bytes)| AttachmentChunk {
+                attachment: self.name.clone(),
+                mime: self.mime.clone(),
+                upload,
+                index: index as u32,
+                count,
+                bytes: bytes.to_vec(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct AttachmentChunk {
+    pub attachment: String,
+    pub mime: String,
+    pub upload: u64,
+    pub index: u32,
+    pub count: u32,
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    pub name: String,
+    pub mime: String,
+    pub len: usize,
+    // the upload that the stored chunks belong to
+    pub upload: u64,
+    pub count: u32,
+    // false while some chunks have not been uploaded yet
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    // Get only
+    Read,
+    // Get and Mut
+    Write,
+}
+
+// Lets the client holding the private key of `grantee` access another
+// client's paste. The server enforces the grant and re-encrypts the paste from
+// the owner's session key to the grantee's. There is no per-paste content key
+// wrapped for each grantee: the server holds every session key and sees the
+// plaintext anyway, so wrapping would hide nothing from it. Grants protect a
+// paste from other clients, not from the server.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Grant {
+    pub grantee: RsaPublicKey,
+    pub permission: Permission,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct EncryptedPaste {
-    pub name: EncryptedData,
+    pub name: EncryptedName,
     pub content: EncryptedData,
+    pub tags: EncryptedData,
 }
 
 impl EncryptedPaste {
@@ -125,81 +725,447 @@ impl EncryptedPaste {
         Ok(Paste {
             name: self.decrypt_name(key)?,
             content: self.decrypt_content(key)?,
+            tags: self.tags.clone().decrypt(key)?,
         })
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum ActionRequest {
-    Get { name: String },
-    Remove { name: String },
-    Mut(Paste),
-    New(Paste),
+    Get {
+        name: String,
+    },
+    Remove {
+        name: String,
+    },
+    // base_revision is the revision the edit started from, None if the paste
+    // is expected not to exist yet
+    Mut {
+        paste: Paste,
+        base_revision: Option<u64>,
+    },
+    New {
+        paste: Paste,
+        expiry: Expiry,
+    },
+    // the nonce is random so that every listing is a distinct request whose
+    // session the server can still find by decrypting it
+    List {
+        nonce: [u8; 16],
+        options: ListOptions,
+    },
+    // whitespace separated keywords, each must occur in the name or content
+    Search {
+        query: String,
+        options: ListOptions,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    History {
+        name: String,
+    },
+    Revision {
+        name: String,
+        revision: u64,
+    },
+    Restore {
+        name: String,
+        revision: u64,
+    },
+    // adds text as a new line at the end of the paste, creating it if needed
+    Append {
+        name: String,
+        text: String,
+    },
+    Attach {
+        name: String,
+        chunk: AttachmentChunk,
+    },
+    Attachments {
+        name: String,
+    },
+    Download {
+        name: String,
+        attachment: String,
+        index: u32,
+    },
+    Detach {
+        name: String,
+        attachment: String,
+    },
+    // moves every paste in the folder `from` and its subfolders into `to`
+    MoveFolder {
+        from: String,
+        to: String,
+    },
+    // removes every paste in the folder and its subfolders
+    RemoveFolder {
+        folder: String,
+    },
+    // replaces any earlier grant to the same grantee
+    Grant {
+        name: String,
+        grant: Grant,
+    },
+    Revoke {
+        name: String,
+        grantee: RsaPublicKey,
+    },
+    Grants {
+        name: String,
+    },
+    // a dummy request of cover traffic, answered with Done
+    Cover,
 }
 
 impl ActionRequest {
+    // Encrypts the request as one value, so that not even its kind shows.
+    pub fn seal(&self, key: &AesKey) -> serde_cbor::Result<EncryptedActionRequest> {
+        Ok(EncryptedActionRequest::Sealed {
+            request: EncryptedData::encrypt_padded(self, key, padding())?,
+        })
+    }
+
     pub fn encrypt(&self, key: &AesKey) -> serde_cbor::Result<EncryptedActionRequest> {
         Ok(match self {
             ActionRequest::Get { name } => EncryptedActionRequest::Get {
-                name: EncryptedData::encrypt(&name, key)?,
+                name: EncryptedName::encrypt(name, key)?,
             },
             ActionRequest::Remove { name } => EncryptedActionRequest::Remove {
-                name: EncryptedData::encrypt(&name, key)?,
+                name: EncryptedName::encrypt(name, key)?,
+            },
+            ActionRequest::Mut {
+                paste,
+                base_revision,
+            } => EncryptedActionRequest::Mut {
+                paste: paste.encrypt(key)?,
+                base_revision: EncryptedData::encrypt(&base_revision, key)?,
+            },
+            ActionRequest::New { paste, expiry } => EncryptedActionRequest::New {
+                paste: paste.encrypt(key)?,
+                expiry: EncryptedData::encrypt(&expiry, key)?,
+            },
+            ActionRequest::List { nonce, options } => EncryptedActionRequest::List {
+                nonce: EncryptedData::encrypt(&nonce, key)?,
+                options: EncryptedData::encrypt(&options, key)?,
+            },
+            ActionRequest::Search { query, options } => EncryptedActionRequest::Search {
+                query: EncryptedData::encrypt(&query, key)?,
+                options: EncryptedData::encrypt(&options, key)?,
+            },
+            ActionRequest::Rename { from, to } => EncryptedActionRequest::Rename {
+                from: EncryptedName::encrypt(from, key)?,
+                to: EncryptedName::encrypt(to, key)?,
+            },
+            ActionRequest::History { name } => EncryptedActionRequest::History {
+                name: EncryptedName::encrypt(name, key)?,
+            },
+            ActionRequest::Revision { name, revision } => EncryptedActionRequest::Revision {
+                name: EncryptedName::encrypt(name, key)?,
+                revision: EncryptedData::encrypt(&revision, key)?,
+            },
+            ActionRequest::Restore { name, revision } => EncryptedActionRequest::Restore {
+                name: EncryptedName::encrypt(name, key)?,
+                revision: EncryptedData::encrypt(&revision, key)?,
+            },
+            ActionRequest::Append { name, text } => EncryptedActionRequest::Append {
+                name: EncryptedName::encrypt(name, key)?,
+                text: EncryptedData::encrypt_padded(&text, key, padding())?,
             },
-            ActionRequest::Mut(paste) => EncryptedActionRequest::Mut(paste.encrypt(key)?),
-            ActionRequest::New(paste) => EncryptedActionRequest::New(paste.encrypt(key)?),
+            ActionRequest::Attach { name, chunk } => EncryptedActionRequest::Attach {
+                name: EncryptedName::encrypt(name, key)?,
+                chunk: EncryptedData::encrypt_padded(&chunk, key, padding())?,
+            },
+            ActionRequest::Attachments { name } => EncryptedActionRequest::Attachments {
+                name: EncryptedName::encrypt(name, key)?,
+            },
+            ActionRequest::Download {
+                name,
+                attachment,
+                index,
+            } => EncryptedActionRequest::Download {
+                name: EncryptedName::encrypt(name, key)?,
+                attachment: EncryptedName::encrypt(attachment, key)?,
+                index: EncryptedData::encrypt(&index, key)?,
+            },
+            ActionRequest::Detach { name, attachment } => EncryptedActionRequest::Detach {
+                name: EncryptedName::encrypt(name, key)?,
+                attachment: EncryptedName::encrypt(attachment, key)?,
+            },
+            ActionRequest::MoveFolder { from, to } => EncryptedActionRequest::MoveFolder {
+                from: EncryptedData::encrypt(&from, key)?,
+                to: EncryptedData::encrypt(&to, key)?,
+            },
+            ActionRequest::RemoveFolder { folder } => EncryptedActionRequest::RemoveFolder {
+                folder: EncryptedData::encrypt(&folder, key)?,
+            },
+            ActionRequest::Grant { name, grant } => EncryptedActionRequest::Grant {
+                name: EncryptedName::encrypt(name, key)?,
+                grant: EncryptedData::encrypt(&grant, key)?,
+            },
+            ActionRequest::Revoke { name, grantee } => EncryptedActionRequest::Revoke {
+                name: EncryptedName::encrypt(name, key)?,
+                grantee: EncryptedData::encrypt(&grantee, key)?,
+            },
+            ActionRequest::Grants { name } => EncryptedActionRequest::Grants {
+                name: EncryptedName::encrypt(name, key)?,
+            },
+            ActionRequest::Cover => self.seal(key)?,
         })
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum EncryptedActionRequest {
-    New(EncryptedPaste),
-    Mut(EncryptedPaste),
-    Get { name: EncryptedData },
-    Remove { name: EncryptedData },
+    New {
+        paste: EncryptedPaste,
+        expiry: EncryptedData,
+    },
+    Mut {
+        paste: EncryptedPaste,
+        base_revision: EncryptedData,
+    },
+    Get {
+        name: EncryptedName,
+    },
+    Remove {
+        name: EncryptedName,
+    },
+    List {
+        nonce: EncryptedData,
+        options: EncryptedData,
+    },
+    Search {
+        query: EncryptedData,
+        options: EncryptedData,
+    },
+    Rename {
+        from: EncryptedName,
+        to: EncryptedName,
+    },
+    History {
+        name: EncryptedName,
+    },
+    Revision {
+        name: EncryptedName,
+        revision: EncryptedData,
+    },
+    Restore {
+        name: EncryptedName,
+        revision: EncryptedData,
+    },
+    Append {
+        name: EncryptedName,
+        text: EncryptedData,
+    },
+    Attach {
+        name: EncryptedName,
+        chunk: EncryptedData,
+    },
+    Attachments {
+        name: EncryptedName,
+    },
+    Download {
+        name: EncryptedName,
+        attachment: EncryptedName,
+        index: EncryptedData,
+    },
+    Detach {
+        name: EncryptedName,
+        attachment: EncryptedName,
+    },
+    MoveFolder {
+        from: EncryptedData,
+        to: EncryptedData,
+    },
+    RemoveFolder {
+        folder: EncryptedData,
+    },
+    Grant {
+        name: EncryptedName,
+        grant: EncryptedData,
+    },
+    Revoke {
+        name: EncryptedName,
+        grantee: EncryptedData,
+    },
+    Grants {
+        name: EncryptedName,
+    },
+    Sealed {
+        request: EncryptedData,
+    },
 }
 
 impl EncryptedActionRequest {
     pub fn decrypt(self, key: &AesKey) -> serde_cbor::Result<ActionRequest> {
         Ok(match self {
-            EncryptedActionRequest::New(paste) => ActionRequest::New(paste.decrypt(key)?),
-            EncryptedActionRequest::Mut(paste) => ActionRequest::Mut(paste.decrypt(key)?),
+            EncryptedActionRequest::New { paste, expiry } => ActionRequest::New {
+                paste: paste.decrypt(key)?,
+                expiry: expiry.decrypt(key)?,
+            },
+            EncryptedActionRequest::Mut {
+                paste,
+                base_revision,
+            } => ActionRequest::Mut {
+                paste: paste.decrypt(key)?,
+                base_revision: base_revision.decrypt(key)?,
+            },
             EncryptedActionRequest::Get { name } => ActionRequest::Get {
                 name: name.decrypt(key)?,
             },
             EncryptedActionRequest::Remove { name } => ActionRequest::Remove {
                 name: name.decrypt(key)?,
             },
+            EncryptedActionRequest::List { nonce, options } => ActionRequest::List {
+                nonce: nonce.decrypt(key)?,
+                options: options.decrypt(key)?,
+            },
+            EncryptedActionRequest::Search { query, options } => ActionRequest::Search {
+                query: query.decrypt(key)?,
+                options: options.decrypt(key)?,
+            },
+            EncryptedActionRequest::Rename { from, to } => ActionRequest::Rename {
+                from: from.decrypt(key)?,
+                to: to.decrypt(key)?,
+            },
+            EncryptedActionRequest::History { name } => ActionRequest::History {
+                name: name.decrypt(key)?,
+            },
+            EncryptedActionRequest::Revision { name, revision } => ActionRequest::Revision {
+                name: name.decrypt(key)?,
+                revision: revision.decrypt(key)?,
+            },
+            EncryptedActionRequest::Restore { name, revision } => ActionRequest::Restore {
+                name: name.decrypt(key)?,
+                revision: revision.decrypt(key)?,
+            },
+            EncryptedActionRequest::Append { name, text } => ActionRequest::Append {
+                name: name.decrypt(key)?,
+                text: text.decrypt(key)?,
+            },
+            EncryptedActionRequest::Attach { name, chunk } => ActionRequest::Attach {
+                name: name.decrypt(key)?,
+                chunk: chunk.decrypt(key)?,
+            },
+            EncryptedActionRequest::Attachments { name } => ActionRequest::Attachments {
+                name: name.decrypt(key)?,
+            },
+            EncryptedActionRequest::Download {
+                name,
+                attachment,
+                index,
+            } => ActionRequest::Download {
+                name: name.decrypt(key)?,
+                attachment: attachment.decrypt(key)?,
+                index: index.decrypt(key)?,
+            },
+            EncryptedActionRequest::Detach { name, attachment } => ActionRequest::Detach {
+                name: name.decrypt(key)?,
+                attachment: attachment.decrypt(key)?,
+            },
+            EncryptedActionRequest::MoveFolder { from, to } => ActionRequest::MoveFolder {
+                from: from.decrypt(key)?,
+                to: to.decrypt(key)?,
+            },
+            EncryptedActionRequest::RemoveFolder { folder } => ActionRequest::RemoveFolder {
+                folder: folder.decrypt(key)?,
+            },
+            EncryptedActionRequest::Grant { name, grant } => ActionRequest::Grant {
+                name: name.decrypt(key)?,
+                grant: grant.decrypt(key)?,
+            },
+            EncryptedActionRequest::Revoke { name, grantee } => ActionRequest::Revoke {
+                name: name.decrypt(key)?,
+                grantee: grantee.decrypt(key)?,
+            },
+            EncryptedActionRequest::Grants { name } => ActionRequest::Grants {
+                name: name.decrypt(key)?,
+            },
+            EncryptedActionRequest::Sealed { request } => request.decrypt(key)?,
         })
     }
 
     pub fn to_response(
         self,
-        payload: Either<Option<EncryptedPaste>, EncryptedAesKey>,
+        payload: Either<EncryptedData, EncryptedAesKey>,
     ) -> EncryptedActionResponse {
         (self, payload)
     }
 
-    pub fn name(&self) -> &EncryptedData {
+    pub fn name(&self) -> Option<&EncryptedName> {
         match self {
-            EncryptedActionRequest::New(EncryptedPaste { name, .. }) => name,
-            EncryptedActionRequest::Mut(EncryptedPaste { name, .. }) => name,
-            EncryptedActionRequest::Get { name } => name,
-            EncryptedActionRequest::Remove { name } => name,
+            EncryptedActionRequest::New {
+                paste: EncryptedPaste { name, .. },
+                ..
+            } => Some(name),
+            EncryptedActionRequest::Mut {
+                paste: EncryptedPaste { name, .. },
+                ..
+            } => Some(name),
+            EncryptedActionRequest::Get { name } => Some(name),
+            EncryptedActionRequest::Remove { name } => Some(name),
+            EncryptedActionRequest::Rename { from, .. } => Some(from),
+            EncryptedActionRequest::History { name } => Some(name),
+            EncryptedActionRequest::Revision { name, .. } => Some(name),
+            EncryptedActionRequest::Restore { name, .. } => Some(name),
+            EncryptedActionRequest::Append { name, .. } => Some(name),
+            EncryptedActionRequest::Attach { name, .. } => Some(name),
+            EncryptedActionRequest::Attachments { name } => Some(name),
+            EncryptedActionRequest::Download { name, .. } => Some(name),
+            EncryptedActionRequest::Detach { name, .. } => Some(name),
+            EncryptedActionRequest::Grant { name, .. } => Some(name),
+            EncryptedActionRequest::Revoke { name, .. } => Some(name),
+            EncryptedActionRequest::Grants { name } => Some(name),
+            EncryptedActionRequest::List { .. } => None,
+            EncryptedActionRequest::Search { .. } => None,
+            EncryptedActionRequest::MoveFolder { .. } => None,
+            EncryptedActionRequest::RemoveFolder { .. } => None,
+            EncryptedActionRequest::Sealed { .. } => None,
         }
     }
 
+    // Every name in the request, the one of the paste first.
+    pub fn names(&self) -> Vec<&EncryptedName> {
+        let mut names: Vec<_> = self.name().into_iter().collect();
+        match self {
+            EncryptedActionRequest::Rename { to, .. } => names.push(to),
+            EncryptedActionRequest::Download { attachment, .. }
+            | EncryptedActionRequest::Detach { attachment, .. } => names.push(attachment),
+            _ => {}
+        }
+        names
+    }
+
     pub fn paste(&self) -> Option<&EncryptedPaste> {
         match self {
-            EncryptedActionRequest::New(paste) => Some(paste),
-            EncryptedActionRequest::Mut(paste) => Some(paste),
+            EncryptedActionRequest::New { paste, .. } => Some(paste),
+            EncryptedActionRequest::Mut { paste, .. } => Some(paste),
             EncryptedActionRequest::Get { .. } => None,
             EncryptedActionRequest::Remove { .. } => None,
+            EncryptedActionRequest::List { .. } => None,
+            EncryptedActionRequest::Search { .. } => None,
+            EncryptedActionRequest::Rename { .. } => None,
+            EncryptedActionRequest::History { .. } => None,
+            EncryptedActionRequest::Revision { .. } => None,
+            EncryptedActionRequest::Restore { .. } => None,
+            EncryptedActionRequest::Append { .. } => None,
+            EncryptedActionRequest::Attach { .. } => None,
+            EncryptedActionRequest::Attachments { .. } => None,
+            EncryptedActionRequest::Download { .. } => None,
+            EncryptedActionRequest::Detach { .. } => None,
+            EncryptedActionRequest::MoveFolder { .. } => None,
+            EncryptedActionRequest::RemoveFolder { .. } => None,
+            EncryptedActionRequest::Grant { .. } => None,
+            EncryptedActionRequest::Revoke { .. } => None,
+            EncryptedActionRequest::Grants { .. } => None,
+            EncryptedActionRequest::Sealed { .. } => None,
         }
     }
 
-    pub fn as_get(&self) -> Option<&EncryptedData> {
+    pub fn as_get(&self) -> Option<&EncryptedName> {
         if let Self::Get { name } = self {
             Some(name)
         } else {
@@ -207,7 +1173,7 @@ impl EncryptedActionRequest {
         }
     }
 
-    pub fn as_remove(&self) -> Option<&EncryptedData> {
+    pub fn as_remove(&self) -> Option<&EncryptedName> {
         if let Self::Remove { name } = self {
             Some(name)
         } else {
@@ -216,7 +1182,11 @@ impl EncryptedActionRequest {
     }
 
     pub fn as_new(&self) -> Option<&EncryptedPaste> {
-        if let Self::New(encrypted_paste) = self {
+        if let Self::New {
+            paste: encrypted_paste,
+            ..
+        } = self
+        {
             Some(encrypted_paste)
         } else {
             None
@@ -224,7 +1194,11 @@ impl EncryptedActionRequest {
     }
 
     pub fn as_mut(&self) -> Option<&EncryptedPaste> {
-        if let Self::Mut(encrypted_paste) = self {
+        if let Self::Mut {
+            paste: encrypted_paste,
+            ..
+        } = self
+        {
             Some(encrypted_paste)
         } else {
             None
@@ -232,17 +1206,152 @@ impl EncryptedActionRequest {
     }
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ActionError {
+    NotFound,
+    AlreadyExists,
+    QuotaExceeded,
+    Forbidden,
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ActionError::NotFound => "paste not found",
+            ActionError::AlreadyExists => "paste already exists",
+            ActionError::QuotaExceeded => "paste quota exceeded",
+            ActionError::Forbidden => "action on paste is forbidden",
+        })
+    }
+}
+
+impl std::error::Error for ActionError {}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct PasteInfo {
+    pub name: String,
+    // length of the content in bytes
+    pub len: usize,
+    #[zeroize(skip)]
+    pub created: SystemTime,
+    #[zeroize(skip)]
+    pub modified: SystemTime,
+    pub tags: Vec<String>,
+    // what another client's grant allows, None for the client's own pastes
+    #[zeroize(skip)]
+    pub shared: Option<Permission>,
+    #[zeroize(skip)]
+    pub expires_at: Option<SystemTime>,
+    // None if the paste is not deleted after some number of reads
+    pub reads_left: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum SortBy {
+    #[default]
+    Name,
+    Created,
+    Modified,
+    Len,
+}
+
+// Which pastes a List or Search returns and in what order.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ListOptions {
+    // only pastes in this folder and its subfolders, all of them if empty
+    pub folder: String,
+    // a paste must carry every one of them
+    pub tags: Vec<String>,
+    pub sort_by: SortBy,
+    pub descending: bool,
+}
+
+impl ListOptions {
+    pub fn matches(&self, paste_info: &PasteInfo) -> bool {
+        in_folder(&paste_info.name, &self.folder)
+            && self.tags.iter().all(|tag| paste_info.tags.contains(tag))
+    }
+
+    pub fn compare(&self, a: &PasteInfo, b: &PasteInfo) -> Ordering {
+        let ordering = match self.sort_by {
+            SortBy::Name => Ordering::Equal,
+            SortBy::Created => a.created.cmp(&b.created),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+            SortBy::Len => a.len.cmp(&b.len),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub revision: u64,
+    pub len: usize,
+    pub modified: SystemTime,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct SearchMatch {
+    pub info: PasteInfo,
+    // part of the content around the first keyword found in it
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ActionResponse {
+    Done,
+    // the paste now has the given revision
+    Written { revision: u64 },
+    Paste { paste: Paste, revision: u64 },
+    // a Mut was based on an older revision than the current one
+    Conflict { paste: Paste, revision: u64 },
+    List(Vec<PasteInfo>),
+    Matches(Vec<SearchMatch>),
+    // newest first, starting with the current revision
+    History(Vec<RevisionInfo>),
+    Attachments(Vec<AttachmentInfo>),
+    Chunk(AttachmentChunk),
+    Grants(Vec<Grant>),
+    Error(ActionError),
+}
+
+impl ActionResponse {
+    pub fn encrypt(&self, key: &AesKey) -> serde_cbor::Result<EncryptedData> {
+        EncryptedData::encrypt_padded(self, key, padding())
+    }
+}
+
+// Left is an encrypted ActionResponse, Right a fresh session key sent instead
+// of handling a request that was encrypted with an outdated one.
 pub type EncryptedActionResponse = (
     EncryptedActionRequest,
-    Either<Option<EncryptedPaste>, EncryptedAesKey>,
+    Either<EncryptedData, EncryptedAesKey>,
 );
 
+// Sent in the clear when the server cannot answer with an encrypted response,
+// so it must never carry anything beyond the reason itself.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum RejectReason {
+    // no session key known to the server decrypts the request, greet again
+    UnknownSession,
+    // the server failed while handling the request
+    Internal,
+}
+
+pub type Rejection = (EncryptedActionRequest, RejectReason);
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Msg {
     GreetRequest(GreetRequest),
     GreetResponse(GreetResponse),
     EncryptedActionRequest(EncryptedActionRequest),
     EncryptedActionResponse(EncryptedActionResponse),
+    Rejection(Rejection),
 }
 
 impl Msg {
@@ -278,6 +1387,23 @@ impl Msg {
         }
     }
 
+    pub fn as_rejection(&self) -> Option<&Rejection> {
+        if let Self::Rejection(rejection) = self {
+            Some(rejection)
+        } else {
+            None
+        }
+    }
+
+    pub fn answered_request(&self) -> Option<&EncryptedActionRequest> {
+        match self {
+            Self::EncryptedActionResponse((request, _)) | Self::Rejection((request, _)) => {
+                Some(request)
+            }
+            _ => None,
+        }
+    }
+
     pub fn greet_request(self) -> Option<GreetRequest> {
         if let Self::GreetRequest(request) = self {
             Some(request)
@@ -309,22 +1435,180 @@ impl Msg {
             None
         }
     }
+
+    pub fn rejection(self) -> Option<Rejection> {
+        if let Self::Rejection(rejection) = self {
+            Some(rejection)
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use generic_array::GenericArray;
-    use rand::{thread_rng, Rng};
-    use std::array;
+    use rand::thread_rng;
+    use serde::Serialize;
+    use typenum::consts::U16;
 
-    use crate::EncryptedData;
+    use crate::{
+        AesKey, EncryptedData, EncryptedName, Padding, MAX_INFLATED_LEN, MAX_PADDING_STEP,
+    };
 
     #[test]
     fn encrypted_data() {
-        let key = GenericArray::from(array::from_fn(|_| thread_rng().gen()));
+        let key = AesKey::generate(&mut thread_rng());
         let data = "Hello world".to_string();
         let encrypted_data = EncryptedData::encrypt(&data, &key).unwrap();
         let decrypted_data: String = encrypted_data.decrypt(&key).unwrap();
         assert_eq!(data, decrypted_data);
     }
+
+    #[test]
+    fn compressed_encrypted_data() {
+        let key = AesKey::generate(&mut thread_rng());
+        let data = "Hello world\n".repeat(100);
+        let encrypted_data = EncryptedData::encrypt(&data, &key).unwrap();
+        assert!(encrypted_data.compressed);
+        assert!(encrypted_data.plaintext_len() < data.len() / 4);
+        let decrypted_data: String = encrypted_data.decrypt(&key).unwrap();
+        assert_eq!(data, decrypted_data);
+    }
+
+    #[test]
+    fn legacy_encrypted_data_json() {
+        #[derive(Serialize)]
+        struct LegacyEncryptedData {
+            blocks: Vec<GenericArray<u8, U16>>,
+            last_block_len: usize,
+        }
+
+        let key = AesKey::generate(&mut thread_rng());
+        // long enough for the blocks to outweigh the fields the legacy format
+        // lacks, and not compressed, which it cannot express
+        let data = "Pack my box with five dozen liquor jugs".to_string();
+        let encrypted_data = EncryptedData {
+            iv: None,
+            mac: None,
+            ..EncryptedData::encrypt(&data, &key).unwrap()
+        };
+        let legacy_json = serde_json::to_string(&LegacyEncryptedData {
+            blocks: encrypted_data.blocks.clone(),
+            last_block_len: encrypted_data.last_block_len,
+        })
+        .unwrap();
+        let json = serde_json::to_string(&encrypted_data).unwrap();
+        assert!(json.len() < legacy_json.len());
+        for json in [json, legacy_json] {
+            let decoded: EncryptedData = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded, encrypted_data);
+        }
+    }
+
+    #[test]
+    fn other_key_or_altered_data() {
+        let mut rng = thread_rng();
+        let key = AesKey::generate(&mut rng);
+        let encrypted_data = EncryptedData::encrypt(&"Hello", &key).unwrap();
+        for _ in 0..1000 {
+            let other_key = AesKey::generate(&mut rng);
+            assert!(encrypted_data
+                .clone()
+                .decrypt::<String>(&other_key)
+                .is_err());
+        }
+        let mut altered = encrypted_data.clone();
+        altered.blocks[0][0] ^= 1;
+        assert!(altered.decrypt::<String>(&key).is_err());
+        let stripped = EncryptedData {
+            mac: None,
+            ..encrypted_data.clone()
+        };
+        assert!(stripped.decrypt::<String>(&key).is_err());
+        let new_key = AesKey::generate(&mut rng);
+        let rekeyed = encrypted_data.rekey(&key, &new_key).unwrap();
+        assert!(rekeyed.clone().decrypt::<String>(&key).is_err());
+        assert_eq!(rekeyed.decrypt::<String>(&new_key).unwrap(), "Hello");
+    }
+
+    #[test]
+    fn invalid_last_block_len() {
+        let key = AesKey::generate(&mut thread_rng());
+        for last_block_len in [0, 17, 99] {
+            let encrypted_data = EncryptedData {
+                last_block_len,
+                ..EncryptedData::encrypt(&"Hello world", &key).unwrap()
+            };
+            assert!(encrypted_data.plaintext_len() <= 16);
+            assert!(encrypted_data.decrypt::<String>(&key).is_err());
+        }
+    }
+
+    #[test]
+    fn inflated_len_is_bounded() {
+        let key = AesKey::generate(&mut thread_rng());
+        let data = "a".repeat(MAX_INFLATED_LEN as usize + 1);
+        let encrypted_data = EncryptedData::encrypt(&data, &key).unwrap();
+        assert!(encrypted_data.compressed);
+        assert!(encrypted_data.decrypt::<String>(&key).is_err());
+    }
+
+    #[test]
+    fn encrypted_name() {
+        let key = AesKey::generate(&mut thread_rng());
+        let other_key = AesKey::generate(&mut thread_rng());
+        let a = EncryptedName::encrypt("work/notes", &key).unwrap();
+        assert!(a.is_bound(&key).unwrap());
+        let forged = EncryptedName {
+            index: EncryptedName::encrypt("work/other", &key).unwrap().index,
+            ..a.clone()
+        };
+        assert!(!forged.is_bound(&key).unwrap());
+        let b = EncryptedName::encrypt("work/notes", &key).unwrap();
+        assert_eq!(a.index, b.index);
+        assert_ne!(a.name, b.name);
+        assert_ne!(
+            a.index,
+            EncryptedName::encrypt("work/note", &key).unwrap().index
+        );
+        assert_ne!(
+            a.index,
+            EncryptedName::encrypt("work/notes", &other_key)
+                .unwrap()
+                .index
+        );
+        assert_eq!(b.decrypt(&key).unwrap(), "work/notes");
+    }
+
+    #[test]
+    fn padded_encrypted_data() {
+        let key = AesKey::generate(&mut thread_rng());
+        for padding in [Padding::PowerOfTwo, Padding::Steps(4096)] {
+            let short = "Hello world, padded".to_string();
+            let long = "Hello world, padded to 32".to_string();
+            let a = EncryptedData::encrypt_padded(&short, &key, padding).unwrap();
+            let b = EncryptedData::encrypt_padded(&long, &key, padding).unwrap();
+            assert_eq!(a.blocks.len(), b.blocks.len());
+            assert_eq!(a.last_block_len, b.last_block_len);
+            assert_eq!(a.decrypt::<String>(&key).unwrap(), short);
+            assert_eq!(b.decrypt::<String>(&key).unwrap(), long);
+        }
+        assert_eq!(Padding::Steps(4096).padded_len(5000), 8192);
+        assert_eq!(Padding::PowerOfTwo.padded_len(5000), 8192);
+        assert_eq!(Padding::None.padded_len(5000), 5000);
+    }
+
+    #[test]
+    fn padding_from_str() {
+        assert_eq!("".parse(), Ok(Padding::None));
+        assert_eq!(" pow2 ".parse(), Ok(Padding::PowerOfTwo));
+        assert_eq!("4096".parse(), Ok(Padding::Steps(4096)));
+        let max_step = MAX_PADDING_STEP.to_string();
+        assert_eq!(max_step.parse(), Ok(Padding::Steps(MAX_PADDING_STEP)));
+        let too_large = (MAX_PADDING_STEP + 1).to_string();
+        for s in ["0", "-1", "pow3", &too_large, &usize::MAX.to_string()] {
+            assert!(s.parse::<Padding>().is_err(), "{s}");
+        }
+    }
 }
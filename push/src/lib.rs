@@ -0,0 +1,124 @@
+use std::{
+    env,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use gist::GistId;
+use msg::Msg;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+pub const ADDR_VAR: &str = "SAFE_NOTEPAD_PUSH_ADDR";
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+const RECONNECT_PERIOD: Duration = Duration::from_secs(5);
+
+// Events a subscriber may fall behind by before it is dropped.
+const MAX_QUEUED_EVENTS: usize = 64;
+
+pub fn addr() -> String {
+    env::var(ADDR_VAR).unwrap_or_else(|_| DEFAULT_ADDR.into())
+}
+
+// Server side of the server-sent events channel. Every msg passed to `send` is
+// pushed to all connected subscribers as a `data: [gist_id, msg]` event. Each
+// subscriber is served by its own thread, so `send` never waits on the network.
+#[derive(Debug, Clone, Default)]
+pub struct Broadcaster {
+    subscribers: Arc<Mutex<Vec<SyncSender<Arc<str>>>>>,
+}
+
+impl Broadcaster {
+    pub fn bind(addr: &str) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let broadcaster = Self::default();
+        let subscribers = broadcaster.subscribers.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let subscribers = subscribers.clone();
+                thread::spawn(move || serve(stream, &subscribers));
+            }
+        });
+        Ok(broadcaster)
+    }
+
+    pub fn send(&self, gist_id: &GistId, msg: &Msg) -> anyhow::Result<()> {
+        let event: Arc<str> =
+            format!("data: {}\n\n", serde_json::to_string(&(gist_id, msg))?).into();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+        Ok(())
+    }
+}
+
+// Writes events to one subscriber until it disconnects or falls too far behind.
+fn serve(stream: TcpStream, subscribers: &Mutex<Vec<SyncSender<Arc<str>>>>) {
+    let Ok(mut stream) = accept(stream) else {
+        return;
+    };
+    let (sender, receiver) = mpsc::sync_channel::<Arc<str>>(MAX_QUEUED_EVENTS);
+    subscribers.lock().unwrap().push(sender);
+    for event in receiver {
+        if stream.write_all(event.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+fn accept(mut stream: TcpStream) -> anyhow::Result<TcpStream> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? != 0 && !line.trim_end().is_empty() {
+        line.clear();
+    }
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
+    )?;
+    Ok(stream)
+}
+
+// Client side of the server-sent events channel. Calls `on_msg` for every
+// pushed msg and silently reconnects whenever the server is unreachable, so
+// callers should keep polling the gists as a fallback.
+pub fn subscribe<F: FnMut(GistId, Msg) + Send + 'static>(
+    addr: String,
+    mut on_msg: F,
+) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        let _ = listen(&addr, &mut on_msg);
+        thread::sleep(RECONNECT_PERIOD);
+    })
+}
+
+fn listen<F: FnMut(GistId, Msg)>(addr: &str, on_msg: &mut F) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: {addr}\r\nAccept: text/event-stream\r\n\r\n"
+    )?;
+    let mut lines = BufReader::new(stream).lines();
+    for line in lines.by_ref() {
+        if line?.trim_end().is_empty() {
+            break;
+        }
+    }
+    for line in lines {
+        if let Some(data) = line?.strip_prefix("data: ") {
+            let (gist_id, msg) = serde_json::from_str(data)?;
+            on_msg(gist_id, msg);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use msg::{
+    AesKey, AttachmentChunk, AttachmentInfo, BlindIndex, EncryptedData, ATTACHMENT_CHUNK_LEN,
+    MAX_ATTACHMENT_LEN,
+};
+use serde_bytes::ByteBuf;
+use std::{mem, path::Path};
+
+pub const MAX_ATTACHMENTS: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct StoredAttachment {
+    name: EncryptedData,
+    mime: EncryptedData,
+    upload: u64,
+    // (encrypted bytes, their length) of every chunk received so far
+    chunks: Vec<Option<(EncryptedData, usize)>>,
+}
+
+impl StoredAttachment {
+    fn new(chunk: &AttachmentChunk, key: &AesKey) -> serde_cbor::Result<Self> {
+        Ok(Self {
+            name: EncryptedData::encrypt(&chunk.attachment, key)?,
+            mime: EncryptedData::encrypt(&chunk.mime, key)?,
+            upload: chunk.upload,
+            chunks: vec![None; chunk.count as usize],
+        })
+    }
+
+    // The name must be a plain file name, since clients save the attachment
+    // under it.
+    pub fn fits(chunk: &AttachmentChunk) -> bool {
+        Path::new(&chunk.attachment).file_name() == Some(chunk.attachment.as_ref())
+            && !chunk.attachment.contains(['/', '\\'])
+            && chunk.index < chunk.count
+            && chunk.bytes.len() <= ATTACHMENT_CHUNK_LEN
+            && chunk.count as usize * ATTACHMENT_CHUNK_LEN <= MAX_ATTACHMENT_LEN
+    }
+
+    // Adds the chunk to the attachment, starting over if the chunk belongs to
+    // another upload.
+    pub fn store(
+        attachment: Option<Self>,
+        mut chunk: AttachmentChunk,
+        key: &AesKey,
+    ) -> serde_cbor::Result<Self> {
+        let mut attachment = match attachment {
+            Some(attachment)
+                if attachment.upload == chunk.upload
+                    && attachment.chunks.len() == chunk.count as usize =>
+            {
+                attachment
+            }
+            _ => Self::new(&chunk, key)?,
+        };
+        let len = chunk.bytes.len();
+        attachment.chunks[chunk.index as usize] = Some((
+            EncryptedData::encrypt(&ByteBuf::from(mem::take(&mut chunk.bytes)), key)?,
+            len,
+        ));
+        Ok(attachment)
+    }
+
+    pub fn index(&self, key: &AesKey) -> serde_cbor::Result<BlindIndex> {
+        Ok(BlindIndex::new(
+            &self.name.clone().decrypt::<String>(key)?,
+            key,
+        ))
+    }
+
+    pub fn info(&self, key: &AesKey) -> serde_cbor::Result<AttachmentInfo> {
+        Ok(AttachmentInfo {
+            name: self.name.clone().decrypt(key)?,
+            mime: self.mime.clone().decrypt(key)?,
+            len: self.chunks.iter().flatten().map(|(_, len)| len).sum(),
+            upload: self.upload,
+            count: self.chunks.len() as u32,
+            complete: self.chunks.iter().all(Option::is_some),
+        })
+    }
+
+    pub fn chunk(&self, index: u32, key: &AesKey) -> serde_cbor::Result<Option<AttachmentChunk>> {
+        let Some(Some((bytes, _))) = self.chunks.get(index as usize) else {
+            return Ok(None);
+        };
+        Ok(Some(AttachmentChunk {
+            attachment: self.name.clone().decrypt(key)?,
+            mime: self.mime.clone().decrypt(key)?,
+            upload: self.upload,
+            index,
+            count: self.chunks.len() as u32,
+            bytes: bytes.clone().decrypt::<ByteBuf>(key)?.into_vec(),
+        }))
+    }
+
+    pub fn rekey(self, key: &AesKey, new_key: &AesKey) -> serde_cbor::Result<Self> {
+        let chunks = self
+            .chunks
+            .into_iter()
+            .map(|chunk| {
+                chunk
+                    .map(|(bytes, len)| Ok((bytes.rekey(key, new_key)?, len)))
+                    .transpose()
+            })
+            .collect::<serde_cbor::Result<_>>()?;
+        Ok(Self {
+            name: self.name.rekey(key, new_key)?,
+            mime: self.mime.rekey(key, new_key)?,
+            chunks,
+            ..self
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use msg::{AesKey, AttachmentChunk, ATTACHMENT_CHUNK_LEN, MAX_ATTACHMENT_LEN};
+    use rand::thread_rng;
+
+    use crate::attachments::StoredAttachment;
+
+    fn chunk(upload: u64, index: u32, count: u32, bytes: &[u8]) -> AttachmentChunk {
+        AttachmentChunk {
+            attachment: "key.txt".into(),
+            mime: "text/plain".into(),
+            upload,
+            index,
+            count,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn store_completes_and_replaces_uploads() {
+        let key = AesKey::generate(&mut thread_rng());
+        let attachment = StoredAttachment::store(None, chunk(1, 1, 2, b"cd"), &key).unwrap();
+        let info = attachment.info(&key).unwrap();
+        assert_eq!((info.len, info.count, info.complete), (2, 2, false));
+        assert_eq!(attachment.chunk(0, &key).unwrap(), None);
+
+        let attachment =
+            StoredAttachment::store(Some(attachment), chunk(1, 0, 2, b"ab"), &key).unwrap();
+        let info = attachment.info(&key).unwrap();
+        assert_eq!((info.len, info.upload, info.complete), (4, 1, true));
+        assert_eq!(
+            attachment.chunk(1, &key).unwrap(),
+            Some(chunk(1, 1, 2, b"cd"))
+        );
+
+        // a chunk of a newer upload drops every chunk of the older one
+        let attachment =
+            StoredAttachment::store(Some(attachment), chunk(2, 0, 2, b"x"), &key).unwrap();
+        let info = attachment.info(&key).unwrap();
+        assert_eq!((info.len, info.upload, info.complete), (1, 2, false));
+        assert_eq!(attachment.chunk(1, &key).unwrap(), None);
+
+        // and so does a chunk of the same upload with another count
+        let attachment =
+            StoredAttachment::store(Some(attachment), chunk(2, 0, 1, b"y"), &key).unwrap();
+        let info = attachment.info(&key).unwrap();
+        assert_eq!((info.len, info.count, info.complete), (1, 1, true));
+    }
+
+    #[test]
+    fn out_of_range_chunks() {
+        let key = AesKey::generate(&mut thread_rng());
+        assert!(StoredAttachment::fits(&chunk(1, 1, 2, b"")));
+        assert!(!StoredAttachment::fits(&chunk(1, 2, 2, b"")));
+        assert!(!StoredAttachment::fits(&chunk(1, u32::MAX, 2, b"")));
+        assert!(!StoredAttachment::fits(&chunk(
+            1,
+            0,
+            1,
+            &[0; ATTACHMENT_CHUNK_LEN + 1]
+        )));
+        let count = (MAX_ATTACHMENT_LEN / ATTACHMENT_CHUNK_LEN) as u32;
+        assert!(StoredAttachment::fits(&chunk(1, 0, count, b"")));
+        assert!(!StoredAttachment::fits(&chunk(1, 0, count + 1, b"")));
+
+        let attachment = StoredAttachment::store(None, chunk(1, 0, 2, b"ab"), &key).unwrap();
+        assert_eq!(attachment.chunk(2, &key).unwrap(), None);
+        assert_eq!(attachment.chunk(u32::MAX, &key).unwrap(), None);
+    }
+
+    #[test]
+    fn names_must_be_file_names() {
+        for (name, fits) in [
+            ("key.txt", true),
+            (".hidden", true),
+            ("a b", true),
+            ("", false),
+            (".", false),
+            ("..", false),
+            ("../../.config/autostart/x.desktop", false),
+            ("/etc/passwd", false),
+            ("dir/", false),
+            ("a\\b", false),
+        ] {
+            let mut chunk = chunk(1, 0, 1, b"");
+            chunk.attachment = name.into();
+            assert_eq!(StoredAttachment::fits(&chunk), fits, "{name}");
+        }
+    }
+}
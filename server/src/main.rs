@@ -1,25 +1,224 @@
+mod attachments;
+mod search;
+
+use attachments::{StoredAttachment, MAX_ATTACHMENTS};
 use either::Either;
-use generic_array::GenericArray;
 use msg::{
-    AesKey, EncryptedActionRequest, EncryptedData, EncryptedPaste, GreetRequest, Msg, RsaPublicKey,
+    ActionError, ActionRequest, ActionResponse, AesKey, AttachmentChunk, BlindIndex,
+    EncryptedActionRequest, EncryptedData, EncryptedName, EncryptedPaste, Expiry, Grant,
+    GreetRequest, ListOptions, Msg, Paste, PasteInfo, Permission, RejectReason, RevisionInfo,
+    RsaPublicKey, SearchMatch,
 };
-use rand::{rngs::ThreadRng, thread_rng, CryptoRng, Rng, RngCore};
+use rand::{rngs::ThreadRng, thread_rng};
 use std::{
-    array,
-    collections::HashMap,
-    time::{Duration, Instant},
+    collections::{HashMap, HashSet, VecDeque},
+    mem, process,
+    time::{Duration, Instant, SystemTime},
 };
 
 const SESSION_KEY_LIFETIME: Duration = Duration::from_secs(120 * 60);
 
+const COLLECT_PERIOD: Duration = Duration::from_secs(1);
+
+// per owner, so that one session cannot use up the space of every other
+const MAX_PASTES: usize = 4096;
+
+const MAX_CONTENT_LEN: usize = 256 * 1024;
+
+const MAX_NAME_LEN: usize = 1024;
+
+// of all tags together
+const MAX_TAGS_LEN: usize = 4 * 1024;
+
+const MAX_SEARCH_MATCHES: usize = 64;
+
+const MAX_REVISIONS: usize = 16;
+
+const MAX_GRANTS: usize = 32;
+
+#[derive(Debug, Clone)]
+struct Revision {
+    revision: u64,
+    content: EncryptedData,
+    len: usize,
+    modified: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+struct StoredPaste {
+    name: EncryptedData,
+    content: EncryptedData,
+    // length of the decrypted content, the ciphertext is deflated and padded
+    len: usize,
+    tags: EncryptedData,
+    // index into session_and_rsa_keys, its fresh session key encrypts the paste
+    owner: usize,
+    created: SystemTime,
+    modified: SystemTime,
+    revision: u64,
+    // previous revisions, oldest first
+    history: VecDeque<Revision>,
+    // (blind index of the attachment name, attachment)
+    attachments: HashMap<BlindIndex, StoredAttachment>,
+    grants: Vec<Grant>,
+    expires_at: Option<SystemTime>,
+    reads_left: Option<u32>,
+}
+
+// How a session may access a paste.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Access {
+    Owner,
+    Shared(Permission),
+}
+
+impl StoredPaste {
+    fn new(
+        name: EncryptedData,
+        content: EncryptedData,
+        len: usize,
+        tags: EncryptedData,
+        owner: usize,
+    ) -> Self {
+        let now = SystemTime::now();
+        Self {
+            name,
+            content,
+            len,
+            tags,
+            owner,
+            created: now,
+            modified: now,
+            revision: 0,
+            history: VecDeque::new(),
+            attachments: HashMap::new(),
+            grants: Vec::new(),
+            expires_at: None,
+            reads_left: None,
+        }
+    }
+
+    // Moves the current content into the history and makes `content` the next revision.
+    fn revise(mut self, content: EncryptedData, len: usize) -> Self {
+        self.history.push_back(Revision {
+            revision: self.revision,
+            content: mem::replace(&mut self.content, content),
+            len: mem::replace(&mut self.len, len),
+            modified: self.modified,
+        });
+        if self.history.len() > MAX_REVISIONS {
+            self.history.pop_front();
+        }
+        self.revision += 1;
+        self.modified = SystemTime::now();
+        self
+    }
+
+    fn index(&self, key: &AesKey) -> serde_cbor::Result<BlindIndex> {
+        Ok(BlindIndex::new(
+            &self.name.clone().decrypt::<String>(key)?,
+            key,
+        ))
+    }
+
+    // The paste as stored under `index`, with `content` instead of the current
+    // content if given.
+    fn to_encrypted_paste(
+        &self,
+        index: BlindIndex,
+        content: Option<&EncryptedData>,
+    ) -> EncryptedPaste {
+        EncryptedPaste {
+            name: EncryptedName {
+                index,
+                name: self.name.clone(),
+            },
+            content: content.unwrap_or(&self.content).clone(),
+            tags: self.tags.clone(),
+        }
+    }
+
+    fn info(&self, key: &AesKey, shared: Option<Permission>) -> serde_cbor::Result<PasteInfo> {
+        Ok(PasteInfo {
+            name: self.name.clone().decrypt(key)?,
+            len: self.len,
+            created: self.created,
+            modified: self.modified,
+            tags: self.tags.clone().decrypt(key)?,
+            shared,
+            expires_at: self.expires_at,
+            reads_left: self.reads_left,
+        })
+    }
+
+    fn revision(&self, revision: u64) -> Option<&EncryptedData> {
+        if revision == self.revision {
+            return Some(&self.content);
+        }
+        self.history
+            .iter()
+            .find(|other| other.revision == revision)
+            .map(|other| &other.content)
+    }
+
+    fn revision_infos(&self) -> Vec<RevisionInfo> {
+        let current = RevisionInfo {
+            revision: self.revision,
+            len: self.len,
+            modified: self.modified,
+        };
+        let history = self.history.iter().rev().map(|revision| RevisionInfo {
+            revision: revision.revision,
+            len: revision.len,
+            modified: revision.modified,
+        });
+        [current].into_iter().chain(history).collect()
+    }
+
+    fn rekey(self, key: &AesKey, new_key: &AesKey) -> serde_cbor::Result<Self> {
+        let history = self
+            .history
+            .into_iter()
+            .map(|revision| {
+                Ok(Revision {
+                    content: revision.content.rekey(key, new_key)?,
+                    ..revision
+                })
+            })
+            .collect::<serde_cbor::Result<_>>()?;
+        let attachments = self
+            .attachments
+            .into_values()
+            .map(|attachment| {
+                let attachment = attachment.rekey(key, new_key)?;
+                Ok((attachment.index(new_key)?, attachment))
+            })
+            .collect::<serde_cbor::Result<_>>()?;
+        Ok(Self {
+            name: self.name.rekey(key, new_key)?,
+            content: self.content.rekey(key, new_key)?,
+            tags: self.tags.rekey(key, new_key)?,
+            history,
+            attachments,
+            ..self
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct State {
     rng: ThreadRng,
     // (old..=fresh session keys, public key, instant when fresh session key was created)
     session_and_rsa_keys: Vec<(Vec<AesKey>, RsaPublicKey, Instant)>,
     msgs: Vec<(gist::GistId, Msg)>,
-    // (name, content)
-    pastes: HashMap<EncryptedData, EncryptedData>,
+    // (blind index of the name, paste)
+    pastes: HashMap<BlindIndex, StoredPaste>,
+    broadcaster: Option<push::Broadcaster>,
+    // msgs and gists queued by drain_requests until the next flush
+    outbox: Vec<Msg>,
+    trash: Vec<gist::GistId>,
+    // gists whose msg could not be decoded, no longer fetched from then on
+    quarantine: HashSet<gist::GistId>,
 }
 
 impl Default for State {
@@ -29,153 +228,1325 @@ impl Default for State {
             session_and_rsa_keys: Default::default(),
             msgs: Default::default(),
             pastes: Default::default(),
+            broadcaster: None,
+            outbox: Default::default(),
+            trash: Default::default(),
+            quarantine: Default::default(),
         }
     }
 }
 
-fn random_session_key<R: CryptoRng + RngCore>(rng: &mut R) -> AesKey {
-    GenericArray::from(array::from_fn(|_| rng.gen()))
-}
-
 impl State {
-    fn remove_paste(&mut self, name: &EncryptedData) -> anyhow::Result<()> {
-        if self.pastes.remove(name).is_some() {
-            for gist_id in self.msgs.iter().filter_map(|msg| {
+    fn respond(&mut self, msg: Msg) {
+        self.outbox.push(msg);
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        let mut trash = mem::take(&mut self.trash);
+        trash.sort_unstable();
+        trash.dedup();
+        let (inserted, removed) = tokio::join!(
+            gist::nonblocking::insert_all(mem::take(&mut self.outbox)),
+            gist::nonblocking::remove_all(trash),
+        );
+        for (msg, gist_id) in inserted? {
+            match gist_id {
+                Ok(gist_id) => {
+                    if let Some(broadcaster) = &self.broadcaster {
+                        broadcaster.send(&gist_id, &msg)?;
+                    }
+                }
+                Err(error) => eprintln!("failed to insert msg: {error:#}"),
+            }
+        }
+        for (gist_id, removed) in removed? {
+            if let Err(error) = removed {
+                eprintln!("failed to remove gist {gist_id}: {error:#}");
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, collected: Vec<(gist::GistId, anyhow::Result<Msg>)>) {
+        self.msgs.clear();
+        for (gist_id, msg) in collected {
+            match msg {
+                Ok(msg) => self.msgs.push((gist_id, msg)),
+                Err(error) if error.is::<serde_json::Error>() => {
+                    if self.quarantine.insert(gist_id.clone()) {
+                        eprintln!("quarantined undecodable gist {gist_id}: {error:#}");
+                    }
+                }
+                Err(error) => eprintln!("failed to fetch gist {gist_id}: {error:#}"),
+            }
+        }
+    }
+
+    fn remove_paste(&mut self, index: &BlindIndex) {
+        if self.pastes.remove(index).is_some() {
+            let refers = |request: &EncryptedActionRequest| {
+                request.name().map(|name| &name.index) == Some(index)
+            };
+            let gist_ids = self.msgs.iter().filter_map(|msg| {
                 msg.1
                     .as_encrypted_action_request()
-                    .map(|request| (request.name() == name).then_some(&msg.0))
-                    .flatten()
+                    .and_then(|request| refers(request).then_some(&msg.0))
                     .or_else(|| {
                         msg.1
                             .as_encrypted_action_response()
-                            .map(|(request, _)| (request.name() == name).then_some(&msg.0))
-                            .flatten()
+                            .and_then(|(request, _)| refers(request).then_some(&msg.0))
                     })
-            }) {
-                gist::remove(gist_id)?;
-            }
+            });
+            self.trash.extend(gist_ids.cloned());
+        }
+    }
+
+    // Counts a read of the paste's content, the last one allowed deletes the paste.
+    fn count_read(&mut self, index: &BlindIndex) {
+        let Some(reads_left) = self
+            .pastes
+            .get_mut(index)
+            .and_then(|paste| paste.reads_left.as_mut())
+        else {
+            return;
+        };
+        *reads_left = reads_left.saturating_sub(1);
+        if *reads_left == 0 {
+            self.remove_paste(index);
+        }
+    }
+
+    fn purge_expired(&mut self) {
+        let now = SystemTime::now();
+        let expired: Vec<_> = self
+            .pastes
+            .iter()
+            .filter(|(_, paste)| paste.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            self.remove_paste(&index);
         }
-        Ok(())
     }
 
-    fn new_paste(
+    // `len` is the length of the decrypted content, deflated content can be
+    // much shorter than that
+    // The limits apply to the decrypted paste, since deflated ciphertext can
+    // be far shorter.
+    fn exceeds_quota(&self, index: &BlindIndex, paste: &Paste, owner: usize) -> bool {
+        paste.content.len() > MAX_CONTENT_LEN
+            || paste.name.len() > MAX_NAME_LEN
+            || paste.tags.iter().map(String::len).sum::<usize>() > MAX_TAGS_LEN
+            || (!self.pastes.contains_key(index)
+                && self
+                    .pastes
+                    .values()
+                    .filter(|paste| paste.owner == owner)
+                    .count()
+                    >= MAX_PASTES)
+    }
+
+    // `paste` is encrypted with the owner's fresh session key.
+    fn insert_paste(
         &mut self,
-        encrypted_request: EncryptedActionRequest,
         paste: EncryptedPaste,
-    ) -> anyhow::Result<()> {
-        assert!(self.pastes.insert(paste.name, paste.content).is_none());
-        gist::insert(&Msg::EncryptedActionResponse(
-            encrypted_request.to_response(Either::Left(None)),
-        ))
-        .map(drop)
+        owner: usize,
+    ) -> anyhow::Result<ActionResponse> {
+        let index = paste.name.index;
+        if self
+            .pastes
+            .get(&index)
+            .is_some_and(|previous| previous.owner != owner)
+        {
+            return Ok(ActionResponse::Error(ActionError::Forbidden));
+        }
+        let decrypted_paste = paste.decrypt(&self.fresh_session_key(owner))?;
+        let len = decrypted_paste.content.len();
+        if self.exceeds_quota(&index, &decrypted_paste, owner) {
+            return Ok(ActionResponse::Error(ActionError::QuotaExceeded));
+        }
+        let previous = self.pastes.get(&index).cloned();
+        self.remove_paste(&index);
+        let stored_paste = match previous {
+            Some(previous) => StoredPaste {
+                name: paste.name.name,
+                tags: paste.tags,
+                ..previous.revise(paste.content, len)
+            },
+            None => StoredPaste::new(paste.name.name, paste.content, len, paste.tags, owner),
+        };
+        let revision = stored_paste.revision;
+        self.pastes.insert(index, stored_paste);
+        Ok(ActionResponse::Written { revision })
+    }
+
+    // The owner's pastes in the folder and its subfolders as (blind index,
+    // decrypted name), none for the root folder so that a folder action never reaches
+    // every paste at once.
+    fn folder_pastes(
+        &self,
+        owner: usize,
+        session_key: &AesKey,
+        folder: &str,
+    ) -> anyhow::Result<Vec<(BlindIndex, String)>> {
+        let mut names = Vec::new();
+        if folder.trim_matches('/').is_empty() {
+            return Ok(names);
+        }
+        for (index, paste) in self.pastes.iter().filter(|(_, paste)| paste.owner == owner) {
+            let decrypted_name: String = paste.name.clone().decrypt(session_key)?;
+            if msg::in_folder(&decrypted_name, folder) {
+                names.push((*index, decrypted_name));
+            }
+        }
+        Ok(names)
+    }
+
+    // Moves all pastes of the folder or none of them.
+    fn move_folder(
+        &mut self,
+        owner: usize,
+        session_key: &AesKey,
+        from: &str,
+        to: &str,
+    ) -> anyhow::Result<ActionResponse> {
+        let mut moves = HashMap::new();
+        for (index, decrypted_name) in self.folder_pastes(owner, session_key, from)? {
+            let moved_name = msg::moved_name(&decrypted_name, from, to).unwrap();
+            if moved_name.len() > MAX_NAME_LEN {
+                return Ok(ActionResponse::Error(ActionError::QuotaExceeded));
+            }
+            moves.insert(index, EncryptedName::encrypt(&moved_name, session_key)?);
+        }
+        if moves.is_empty() {
+            return Ok(ActionResponse::Error(ActionError::NotFound));
+        }
+        if moves
+            .values()
+            .any(|to| self.pastes.contains_key(&to.index) && !moves.contains_key(&to.index))
+        {
+            return Ok(ActionResponse::Error(ActionError::AlreadyExists));
+        }
+        let mut moved = Vec::with_capacity(moves.len());
+        for (from, to) in moves {
+            let paste = self.pastes.get(&from).cloned().unwrap();
+            self.remove_paste(&from);
+            moved.push((
+                to.index,
+                StoredPaste {
+                    name: to.name,
+                    modified: SystemTime::now(),
+                    ..paste
+                },
+            ));
+        }
+        self.pastes.extend(moved);
+        Ok(ActionResponse::Done)
+    }
+
+    fn fresh_session_key(&self, session_index: usize) -> AesKey {
+        self.session_and_rsa_keys[session_index]
+            .0
+            .last()
+            .unwrap()
+            .clone()
+    }
+
+    // The session's own pastes and those shared with it, each with the key
+    // that encrypts it and what the grant allows. A grantee's requests are
+    // served with the owner's key, see Grant for why nothing is wrapped.
+    fn visible_pastes(
+        &self,
+        session_index: usize,
+    ) -> Vec<(&BlindIndex, &StoredPaste, AesKey, Option<Permission>)> {
+        let rsa_public_key = &self.session_and_rsa_keys[session_index].1;
+        self.pastes
+            .iter()
+            .filter_map(|(index, paste)| {
+                let shared = if paste.owner == session_index {
+                    None
+                } else {
+                    let grant = paste
+                        .grants
+                        .iter()
+                        .find(|grant| grant.grantee == *rsa_public_key)?;
+                    Some(grant.permission)
+                };
+                Some((index, paste, self.fresh_session_key(paste.owner), shared))
+            })
+            .collect()
+    }
+
+    // Finds the paste a session refers to by `name`, whose blind index is the
+    // key of the paste in `pastes` only if the session owns it. Returns that
+    // key, the owner's session key and the access of the session.
+    fn find_paste(
+        &self,
+        name: &EncryptedName,
+        session_index: usize,
+        session_key: &AesKey,
+    ) -> anyhow::Result<Option<(BlindIndex, AesKey, Access)>> {
+        if self
+            .pastes
+            .get(&name.index)
+            .is_some_and(|paste| paste.owner == session_index)
+        {
+            return Ok(Some((name.index, session_key.clone(), Access::Owner)));
+        }
+        let decrypted_name = name.clone().decrypt(session_key)?;
+        for (index, _, key, shared) in self.visible_pastes(session_index) {
+            if let Some(permission) = shared {
+                if BlindIndex::new(&decrypted_name, &key) == *index {
+                    return Ok(Some((*index, key, Access::Shared(permission))));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // The key of a paste in `pastes` that the session owns, or why there is none.
+    fn find_owned_paste(
+        &self,
+        name: &EncryptedName,
+        session_index: usize,
+        session_key: &AesKey,
+    ) -> anyhow::Result<Result<BlindIndex, ActionError>> {
+        Ok(match self.find_paste(name, session_index, session_key)? {
+            Some((index, _, Access::Owner)) => Ok(index),
+            Some(_) => Err(ActionError::Forbidden),
+            None => Err(ActionError::NotFound),
+        })
+    }
+
+    // The key of a paste in `pastes` that the session may write to with the
+    // owner's session key, or why there is none.
+    fn find_writable_paste(
+        &self,
+        name: &EncryptedName,
+        session_index: usize,
+        session_key: &AesKey,
+    ) -> anyhow::Result<Result<(BlindIndex, AesKey), ActionError>> {
+        Ok(match self.find_paste(name, session_index, session_key)? {
+            Some((_, _, Access::Shared(Permission::Read))) => Err(ActionError::Forbidden),
+            Some((index, key, _)) => Ok((index, key)),
+            None => Err(ActionError::NotFound),
+        })
     }
 
-    fn drain_requests(&mut self) -> anyhow::Result<()> {
-        'a: for msg_index in (0..self.msgs.len()).rev() {
-            if let Some(request) = self.msgs[msg_index].1.as_greet_request() {
-                if self
-                    .msgs
+    fn list_pastes(
+        &self,
+        session_index: usize,
+        options: &ListOptions,
+    ) -> anyhow::Result<Vec<PasteInfo>> {
+        let mut paste_infos = Vec::new();
+        for (_, paste, key, shared) in self.visible_pastes(session_index) {
+            let paste_info = paste.info(&key, shared)?;
+            if options.matches(&paste_info) {
+                paste_infos.push(paste_info);
+            }
+        }
+        paste_infos.sort_unstable_by(|a, b| options.compare(a, b));
+        Ok(paste_infos)
+    }
+
+    fn search_pastes(
+        &self,
+        session_index: usize,
+        query: &str,
+        options: &ListOptions,
+    ) -> anyhow::Result<Vec<SearchMatch>> {
+        let mut search_matches = Vec::new();
+        for (index, paste, key, shared) in self.visible_pastes(session_index) {
+            let info = paste.info(&key, shared)?;
+            if !options.matches(&info) {
+                continue;
+            }
+            let mut paste = paste.to_encrypted_paste(*index, None).decrypt(&key)?;
+            // a read limited paste is matched by its name only, snippets would
+            // show its content without counting a read
+            if info.reads_left.is_some() {
+                paste.content.clear();
+            }
+            if let Some(snippet) = search::search(&paste, query) {
+                search_matches.push(SearchMatch { info, snippet });
+            }
+        }
+        search_matches.sort_unstable_by(|a, b| options.compare(&a.info, &b.info));
+        search_matches.truncate(MAX_SEARCH_MATCHES);
+        Ok(search_matches)
+    }
+
+    // Pastes follow their owner's fresh session key, so that requests encrypted
+    // with it keep finding them after a rotation.
+    fn rotate_session_key(&mut self, session_index: usize) {
+        let fresh_session_key = AesKey::generate(&mut self.rng);
+        let old_session_key = self.fresh_session_key(session_index);
+        let (session_keys, _, last_session_key_creation_instant) =
+            &mut self.session_and_rsa_keys[session_index];
+        session_keys.push(fresh_session_key.clone());
+        *last_session_key_creation_instant = Instant::now();
+        let owned_indexes: Vec<_> = self
+            .pastes
+            .iter()
+            .filter(|(_, paste)| paste.owner == session_index)
+            .map(|(index, _)| *index)
+            .collect();
+        for index in owned_indexes {
+            let paste = self.pastes.remove(&index).unwrap();
+            let rekeyed = paste
+                .rekey(&old_session_key, &fresh_session_key)
+                .and_then(|paste| Ok((paste.index(&fresh_session_key)?, paste)));
+            match rekeyed {
+                Ok((index, paste)) => {
+                    self.pastes.insert(index, paste);
+                }
+                Err(error) => eprintln!("dropped paste that failed to rekey: {error:#}"),
+            }
+        }
+    }
+
+    fn is_answered(&self, encrypted_request: &EncryptedActionRequest) -> bool {
+        self.msgs
+            .iter()
+            .any(|(_, msg)| msg.answered_request() == Some(encrypted_request))
+    }
+
+    // (index into session_and_rsa_keys, index of the session key that decrypts the request)
+    fn find_session_key(
+        &self,
+        encrypted_request: &EncryptedActionRequest,
+    ) -> Option<(usize, usize)> {
+        self.session_and_rsa_keys.iter().enumerate().find_map(
+            |(session_index, (session_keys, _, _))| {
+                session_keys
                     .iter()
-                    .filter_map(|msg| msg.1.as_greet_response())
-                    .all(|response| &response.0 != request)
-                {
-                    let key = random_session_key(&mut self.rng);
-                    gist::insert(&Msg::GreetResponse(
-                        request.clone().to_response(&mut self.rng, &key)?,
-                    ))
-                    .unwrap();
-                    let rsa_public_key = self.msgs.remove(msg_index).1.greet_request().unwrap().0;
-                    self.session_and_rsa_keys
-                        .push((vec![key], rsa_public_key, Instant::now()));
-                }
-            } else if self.msgs[msg_index]
-                .1
-                .as_encrypted_action_request()
-                .is_some()
+                    .position(|session_key| encrypted_request.clone().decrypt(session_key).is_ok())
+                    .map(|session_key_index| (session_index, session_key_index))
+            },
+        )
+    }
+
+    fn drain_requests(&mut self) {
+        for msg_index in (0..self.msgs.len()).rev() {
+            let gist_id = self.msgs[msg_index].0.clone();
+            if let Err(error) = self.drain_request(msg_index) {
+                eprintln!("failed to handle gist {gist_id}: {error:#}");
+            }
+        }
+    }
+
+    fn drain_request(&mut self, msg_index: usize) -> anyhow::Result<()> {
+        if let Some(request) = self.msgs[msg_index].1.as_greet_request() {
+            if self
+                .msgs
+                .iter()
+                .filter_map(|msg| msg.1.as_greet_response())
+                .all(|response| &response.0 != request)
+            {
+                let key = AesKey::generate(&mut self.rng);
+                let response = request.clone().to_response(&mut self.rng, &key)?;
+                self.respond(Msg::GreetResponse(response));
+                let rsa_public_key = self.msgs.remove(msg_index).1.greet_request().unwrap().0;
+                self.session_and_rsa_keys
+                    .push((vec![key], rsa_public_key, Instant::now()));
+            }
+        } else if self.msgs[msg_index]
+            .1
+            .as_encrypted_action_request()
+            .is_some()
+        {
+            let (gist_id, msg) = self.msgs.remove(msg_index);
+            let encrypted_request = msg.encrypted_action_request().unwrap();
+            if self.is_answered(&encrypted_request) {
+                return Ok(());
+            }
+            let Some((session_index, session_key_index)) =
+                self.find_session_key(&encrypted_request)
+            else {
+                self.respond(Msg::Rejection((
+                    encrypted_request,
+                    RejectReason::UnknownSession,
+                )));
+                self.trash.push(gist_id);
+                return Ok(());
+            };
+            let (session_keys, _, last_session_key_creation_instant) =
+                &self.session_and_rsa_keys[session_index];
+            if session_key_index == session_keys.len().saturating_sub(1)
+                && last_session_key_creation_instant.elapsed() >= SESSION_KEY_LIFETIME
             {
-                let (gist_id, msg) = self.msgs.remove(msg_index);
-                let encrypted_request = msg.encrypted_action_request().unwrap();
+                self.rotate_session_key(session_index);
+            }
+            let (session_keys, rsa_public_key, _) = &self.session_and_rsa_keys[session_index];
+            if session_key_index != session_keys.len().saturating_sub(1) {
+                let encrypted_session_key = GreetRequest(rsa_public_key.clone())
+                    .to_response(&mut self.rng, session_keys.last().unwrap())?
+                    .1;
+                self.respond(Msg::EncryptedActionResponse(
+                    encrypted_request.to_response(Either::Right(encrypted_session_key)),
+                ));
+                self.trash.push(gist_id);
+                return Ok(());
+            }
+            let session_key = session_keys[session_key_index].clone();
+            let response = self
+                .handle_action(&encrypted_request, session_index, &session_key)
+                .and_then(|response| Ok(response.encrypt(&session_key)?));
+            self.trash.push(gist_id);
+            match response {
+                Ok(response) => self.respond(Msg::EncryptedActionResponse(
+                    encrypted_request.to_response(Either::Left(response)),
+                )),
+                Err(error) => {
+                    self.respond(Msg::Rejection((encrypted_request, RejectReason::Internal)));
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
 
-                if self
-                    .msgs
-                    .iter()
-                    .filter_map(|msg| msg.1.as_encrypted_action_response())
-                    .all(|response| response.0 != encrypted_request)
-                {
-                    for (session_keys, rsa_public_key, last_session_key_creation_instant) in
-                        self.session_and_rsa_keys.iter_mut()
-                    {
-                        for session_key_index in 0..session_keys.len() {
-                            if encrypted_request
-                                .clone()
-                                .decrypt(&session_keys[session_key_index])
-                                .is_ok()
-                            {
-                                if session_key_index == session_keys.len().saturating_sub(1) {
-                                    if last_session_key_creation_instant.elapsed()
-                                        >= SESSION_KEY_LIFETIME
-                                    {
-                                        session_keys.push(random_session_key(&mut self.rng));
-                                    }
-                                    if session_key_index != session_keys.len().saturating_sub(1) {
-                                        gist::insert(&Msg::EncryptedActionResponse(
-                                            encrypted_request.to_response(Either::Right(
-                                                GreetRequest(rsa_public_key.clone())
-                                                    .to_response(
-                                                        &mut self.rng,
-                                                        &session_keys.last().unwrap(),
-                                                    )?
-                                                    .1,
-                                            )),
-                                        ))?;
-                                        continue 'a;
-                                    }
-                                }
-                                match encrypted_request.clone() {
-                                    EncryptedActionRequest::Get { name } => {
-                                        if let Some(content) = self.pastes.get(&name) {
-                                            gist::insert(&Msg::EncryptedActionResponse(
-                                                encrypted_request.to_response(Either::Left(Some(
-                                                    EncryptedPaste {
-                                                        name,
-                                                        content: content.clone(),
-                                                    },
-                                                ))),
-                                            ))?;
-                                        }
-                                    }
-                                    EncryptedActionRequest::Remove { name } => {
-                                        self.remove_paste(&name)?;
-                                        gist::remove(&gist_id)?;
-                                    }
-                                    EncryptedActionRequest::New(encrypted_paste) => {
-                                        if !self.pastes.contains_key(&encrypted_paste.name) {
-                                            self.new_paste(encrypted_request, encrypted_paste)?;
-                                            gist::remove(&gist_id)?;
-                                        }
-                                    }
-                                    EncryptedActionRequest::Mut(encrypted_paste) => {
-                                        self.remove_paste(&encrypted_paste.name)?;
-                                        self.new_paste(encrypted_request, encrypted_paste)?;
-                                        gist::remove(&gist_id)?;
-                                    }
-                                }
-                                continue 'a;
-                            }
+    fn handle_action(
+        &mut self,
+        encrypted_request: &EncryptedActionRequest,
+        session_index: usize,
+        session_key: &AesKey,
+    ) -> anyhow::Result<ActionResponse> {
+        // an index paired with someone else's name could reach their paste
+        for name in encrypted_request.names() {
+            if !name.is_bound(session_key)? {
+                return Ok(ActionResponse::Error(ActionError::Forbidden));
+            }
+        }
+        Ok(match encrypted_request.clone() {
+            EncryptedActionRequest::Get { name } => {
+                match self.find_paste(&name, session_index, session_key)? {
+                    Some((index, key, _)) => {
+                        let paste = &self.pastes[&index];
+                        let response = ActionResponse::Paste {
+                            paste: paste.to_encrypted_paste(index, None).decrypt(&key)?,
+                            revision: paste.revision,
+                        };
+                        self.count_read(&index);
+                        response
+                    }
+                    None => ActionResponse::Error(ActionError::NotFound),
+                }
+            }
+            EncryptedActionRequest::Remove { name } => {
+                match self.find_owned_paste(&name, session_index, session_key)? {
+                    Ok(index) => {
+                        self.remove_paste(&index);
+                        ActionResponse::Done
+                    }
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::New {
+                paste: encrypted_paste,
+                expiry,
+            } => {
+                if self.pastes.contains_key(&encrypted_paste.name.index) {
+                    ActionResponse::Error(ActionError::AlreadyExists)
+                } else {
+                    let expiry: Expiry = expiry.decrypt(session_key)?;
+                    let index = encrypted_paste.name.index;
+                    let response = self.insert_paste(encrypted_paste, session_index)?;
+                    if let Some(paste) = self.pastes.get_mut(&index) {
+                        paste.expires_at = expiry.expires_at;
+                        paste.reads_left = expiry.max_reads;
+                    }
+                    response
+                }
+            }
+            EncryptedActionRequest::Rename { from, to } => {
+                match self.find_owned_paste(&from, session_index, session_key)? {
+                    Ok(_) if self.pastes.contains_key(&to.index) => {
+                        ActionResponse::Error(ActionError::AlreadyExists)
+                    }
+                    Ok(_) if to.clone().decrypt(session_key)?.len() > MAX_NAME_LEN => {
+                        ActionResponse::Error(ActionError::QuotaExceeded)
+                    }
+                    Ok(index) => {
+                        let paste = self.pastes[&index].clone();
+                        self.remove_paste(&index);
+                        self.pastes.insert(
+                            to.index,
+                            StoredPaste {
+                                name: to.name,
+                                modified: SystemTime::now(),
+                                ..paste
+                            },
+                        );
+                        ActionResponse::Done
+                    }
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::History { name } => {
+                match self.find_paste(&name, session_index, session_key)? {
+                    Some((index, _, _)) => {
+                        ActionResponse::History(self.pastes[&index].revision_infos())
+                    }
+                    None => ActionResponse::Error(ActionError::NotFound),
+                }
+            }
+            EncryptedActionRequest::Revision { name, revision } => {
+                let revision = revision.decrypt(session_key)?;
+                let found = self
+                    .find_paste(&name, session_index, session_key)?
+                    .and_then(|(index, key, _)| {
+                        let paste = &self.pastes[&index];
+                        let content = paste.revision(revision)?;
+                        Some((index, key, paste.to_encrypted_paste(index, Some(content))))
+                    });
+                match found {
+                    Some((index, key, encrypted_paste)) => {
+                        let response = ActionResponse::Paste {
+                            paste: encrypted_paste.decrypt(&key)?,
+                            revision,
+                        };
+                        self.count_read(&index);
+                        response
+                    }
+                    None => ActionResponse::Error(ActionError::NotFound),
+                }
+            }
+            EncryptedActionRequest::Restore { name, revision } => {
+                let revision = revision.decrypt(session_key)?;
+                match self.find_writable_paste(&name, session_index, session_key)? {
+                    Ok((index, _)) => {
+                        let paste = &self.pastes[&index];
+                        let owner = paste.owner;
+                        match paste
+                            .revision(revision)
+                            .map(|content| paste.to_encrypted_paste(index, Some(content)))
+                        {
+                            Some(encrypted_paste) => self.insert_paste(encrypted_paste, owner)?,
+                            None => ActionResponse::Error(ActionError::NotFound),
                         }
                     }
+                    Err(error) => ActionResponse::Error(error),
                 }
             }
-        }
-        Ok(())
+            EncryptedActionRequest::Mut {
+                paste: encrypted_paste,
+                base_revision,
+            } => {
+                let base_revision: Option<u64> = base_revision.decrypt(session_key)?;
+                match self.find_paste(&encrypted_paste.name, session_index, session_key)? {
+                    Some((_, _, Access::Shared(Permission::Read))) => {
+                        ActionResponse::Error(ActionError::Forbidden)
+                    }
+                    Some((index, key, _)) => {
+                        let current = &self.pastes[&index];
+                        if Some(current.revision) != base_revision {
+                            // the conflict shows the current content, which is a read
+                            let response = ActionResponse::Conflict {
+                                paste: current.to_encrypted_paste(index, None).decrypt(&key)?,
+                                revision: current.revision,
+                            };
+                            self.count_read(&index);
+                            response
+                        } else {
+                            // a grantee's edit is stored under the owner's key
+                            let owner = current.owner;
+                            let encrypted_paste =
+                                encrypted_paste.decrypt(session_key)?.encrypt(&key)?;
+                            self.insert_paste(encrypted_paste, owner)?
+                        }
+                    }
+                    None => self.insert_paste(encrypted_paste, session_index)?,
+                }
+            }
+            EncryptedActionRequest::Append { name, text } => {
+                let text: String = text.decrypt(session_key)?;
+                match self.find_writable_paste(&name, session_index, session_key)? {
+                    Ok((index, key)) => {
+                        let paste = &self.pastes[&index];
+                        let owner = paste.owner;
+                        let mut content: String = paste.content.clone().decrypt(&key)?;
+                        if !content.is_empty() && !content.ends_with('\n') {
+                            content.push('\n');
+                        }
+                        content.push_str(&text);
+                        let encrypted_paste = EncryptedPaste {
+                            content: EncryptedData::encrypt_padded(&content, &key, msg::padding())?,
+                            ..paste.to_encrypted_paste(index, None)
+                        };
+                        self.insert_paste(encrypted_paste, owner)?
+                    }
+                    Err(ActionError::NotFound) => self.insert_paste(
+                        EncryptedPaste {
+                            name,
+                            content: EncryptedData::encrypt_padded(
+                                &text,
+                                session_key,
+                                msg::padding(),
+                            )?,
+                            tags: EncryptedData::encrypt(&Vec::<String>::new(), session_key)?,
+                        },
+                        session_index,
+                    )?,
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::Attach { name, chunk } => {
+                let chunk: AttachmentChunk = chunk.decrypt(session_key)?;
+                match self.find_writable_paste(&name, session_index, session_key)? {
+                    Ok((index, key)) => {
+                        let attachment_index = BlindIndex::new(&chunk.attachment, &key);
+                        let paste = self.pastes.get_mut(&index).unwrap();
+                        if !StoredAttachment::fits(&chunk)
+                            || (!paste.attachments.contains_key(&attachment_index)
+                                && paste.attachments.len() >= MAX_ATTACHMENTS)
+                        {
+                            ActionResponse::Error(ActionError::QuotaExceeded)
+                        } else {
+                            let attachment = paste.attachments.remove(&attachment_index);
+                            paste.attachments.insert(
+                                attachment_index,
+                                StoredAttachment::store(attachment, chunk, &key)?,
+                            );
+                            ActionResponse::Done
+                        }
+                    }
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::Attachments { name } => {
+                match self.find_paste(&name, session_index, session_key)? {
+                    Some((index, key, _)) => {
+                        let mut attachment_infos = self.pastes[&index]
+                            .attachments
+                            .values()
+                            .map(|attachment| attachment.info(&key))
+                            .collect::<serde_cbor::Result<Vec<_>>>()?;
+                        attachment_infos.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+                        ActionResponse::Attachments(attachment_infos)
+                    }
+                    None => ActionResponse::Error(ActionError::NotFound),
+                }
+            }
+            EncryptedActionRequest::Download {
+                name,
+                attachment,
+                index: chunk_index,
+            } => {
+                let chunk_index = chunk_index.decrypt(session_key)?;
+                let chunk = match self.find_paste(&name, session_index, session_key)? {
+                    Some((index, key, _)) => {
+                        // a grantee's attachment names are indexed under the owner's key
+                        let attachment_index =
+                            BlindIndex::new(&attachment.decrypt(session_key)?, &key);
+                        match self.pastes[&index].attachments.get(&attachment_index) {
+                            Some(stored_attachment) => stored_attachment
+                                .chunk(chunk_index, &key)?
+                                .map(|chunk| (index, chunk)),
+                            None => None,
+                        }
+                    }
+                    None => None,
+                };
+                match chunk {
+                    Some((index, chunk)) => {
+                        // the last chunk completes a download, which is a read
+                        if chunk.index + 1 == chunk.count {
+                            self.count_read(&index);
+                        }
+                        ActionResponse::Chunk(chunk)
+                    }
+                    None => ActionResponse::Error(ActionError::NotFound),
+                }
+            }
+            EncryptedActionRequest::Detach { name, attachment } => {
+                match self.find_writable_paste(&name, session_index, session_key)? {
+                    Ok((index, key)) => {
+                        let attachment_index =
+                            BlindIndex::new(&attachment.decrypt(session_key)?, &key);
+                        match self
+                            .pastes
+                            .get_mut(&index)
+                            .unwrap()
+                            .attachments
+                            .remove(&attachment_index)
+                        {
+                            Some(_) => ActionResponse::Done,
+                            None => ActionResponse::Error(ActionError::NotFound),
+                        }
+                    }
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::MoveFolder { from, to } => self.move_folder(
+                session_index,
+                session_key,
+                &from.decrypt::<String>(session_key)?,
+                &to.decrypt::<String>(session_key)?,
+            )?,
+            EncryptedActionRequest::RemoveFolder { folder } => {
+                let names = self.folder_pastes(
+                    session_index,
+                    session_key,
+                    &folder.decrypt::<String>(session_key)?,
+                )?;
+                if names.is_empty() {
+                    ActionResponse::Error(ActionError::NotFound)
+                } else {
+                    for (index, _) in names {
+                        self.remove_paste(&index);
+                    }
+                    ActionResponse::Done
+                }
+            }
+            EncryptedActionRequest::Grant { name, grant } => {
+                let grant: Grant = grant.decrypt(session_key)?;
+                match self.find_owned_paste(&name, session_index, session_key)? {
+                    Ok(index) => {
+                        let grants = &mut self.pastes.get_mut(&index).unwrap().grants;
+                        grants.retain(|other| other.grantee != grant.grantee);
+                        if grants.len() >= MAX_GRANTS {
+                            ActionResponse::Error(ActionError::QuotaExceeded)
+                        } else {
+                            grants.push(grant);
+                            ActionResponse::Done
+                        }
+                    }
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::Revoke { name, grantee } => {
+                let grantee: RsaPublicKey = grantee.decrypt(session_key)?;
+                match self.find_owned_paste(&name, session_index, session_key)? {
+                    Ok(index) => {
+                        let grants = &mut self.pastes.get_mut(&index).unwrap().grants;
+                        let len = grants.len();
+                        grants.retain(|grant| grant.grantee != grantee);
+                        if grants.len() < len {
+                            ActionResponse::Done
+                        } else {
+                            ActionResponse::Error(ActionError::NotFound)
+                        }
+                    }
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::Grants { name } => {
+                match self.find_owned_paste(&name, session_index, session_key)? {
+                    Ok(index) => ActionResponse::Grants(self.pastes[&index].grants.clone()),
+                    Err(error) => ActionResponse::Error(error),
+                }
+            }
+            EncryptedActionRequest::Sealed { request } => {
+                match request.decrypt::<ActionRequest>(session_key)? {
+                    ActionRequest::Cover => ActionResponse::Done,
+                    request => self.handle_action(
+                        &request.encrypt(session_key)?,
+                        session_index,
+                        session_key,
+                    )?,
+                }
+            }
+            EncryptedActionRequest::List { options, .. } => ActionResponse::List(
+                self.list_pastes(session_index, &options.decrypt(session_key)?)?,
+            ),
+            EncryptedActionRequest::Search { query, options } => {
+                ActionResponse::Matches(self.search_pastes(
+                    session_index,
+                    &query.decrypt::<String>(session_key)?,
+                    &options.decrypt(session_key)?,
+                )?)
+            }
+        })
     }
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
+    if let Err(error) = msg::check_settings() {
+        eprintln!("{error}");
+        process::exit(1);
+    }
     let mut state = State::default();
+    match push::Broadcaster::bind(&push::addr()) {
+        Ok(broadcaster) => state.broadcaster = Some(broadcaster),
+        Err(error) => eprintln!("push channel disabled: {error}"),
+    }
 
     loop {
-        state.msgs = gist::collect().unwrap();
-        state.drain_requests().unwrap();
+        match gist::nonblocking::collect_each(&state.quarantine).await {
+            Ok(collected) => {
+                state.receive(collected);
+                state.purge_expired();
+                state.drain_requests();
+            }
+            Err(error) => eprintln!("failed to collect gists: {error:#}"),
+        }
+        if let Err(error) = state.flush().await {
+            eprintln!("failed to flush: {error:#}");
+        }
+        tokio::time::sleep(COLLECT_PERIOD).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use msg::{
+        ActionError, ActionRequest, ActionResponse, AesKey, Attachment, BlindIndex,
+        EncryptedActionRequest, EncryptedName, Expiry, Grant, ListOptions, Paste, Permission,
+        RsaPrivateKey, ATTACHMENT_CHUNK_LEN,
+    };
+    use rand::thread_rng;
+
+    use crate::{State, MAX_CONTENT_LEN, MAX_NAME_LEN, MAX_REVISIONS, MAX_TAGS_LEN};
+
+    // A state with `count` sessions, as if each had greeted the server.
+    fn state(count: usize) -> State {
+        let mut state = State::default();
+        for _ in 0..count {
+            let rsa_private_key = RsaPrivateKey::new(&mut thread_rng(), 512).unwrap();
+            state.session_and_rsa_keys.push((
+                vec![AesKey::generate(&mut thread_rng())],
+                rsa_private_key.to_public_key(),
+                Instant::now(),
+            ));
+        }
+        state
+    }
+
+    fn act(state: &mut State, session_index: usize, request: ActionRequest) -> ActionResponse {
+        let session_key = state.fresh_session_key(session_index);
+        state
+            .handle_action(
+                &request.encrypt(&session_key).unwrap(),
+                session_index,
+                &session_key,
+            )
+            .unwrap()
+    }
+
+    fn paste(name: &str, content: &str) -> Paste {
+        Paste {
+            name: name.into(),
+            content: content.into(),
+            tags: Vec::new(),
+        }
+    }
+
+    fn names(state: &mut State, session_index: usize) -> Vec<String> {
+        let request = ActionRequest::List {
+            nonce: [0; 16],
+            options: ListOptions::default(),
+        };
+        let ActionResponse::List(paste_infos) = act(state, session_index, request) else {
+            panic!("not a list");
+        };
+        let mut names: Vec<_> = paste_infos.iter().map(|info| info.name.clone()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn stale_mut_conflicts() {
+        let mut state = state(1);
+        let edit = |content, base_revision| ActionRequest::Mut {
+            paste: paste("note", content),
+            base_revision,
+        };
+        assert_eq!(
+            act(&mut state, 0, edit("first", None)),
+            ActionResponse::Written { revision: 0 }
+        );
+        assert_eq!(
+            act(&mut state, 0, edit("second", Some(0))),
+            ActionResponse::Written { revision: 1 }
+        );
+        assert_eq!(
+            act(&mut state, 0, edit("third", Some(0))),
+            ActionResponse::Conflict {
+                paste: paste("note", "second"),
+                revision: 1,
+            }
+        );
+        assert_eq!(
+            act(&mut state, 0, edit("third", None)),
+            ActionResponse::Conflict {
+                paste: paste("note", "second"),
+                revision: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn move_folder_collisions() {
+        let mut state = state(1);
+        for name in ["a/x", "a/a/x", "b/x"] {
+            let request = ActionRequest::Mut {
+                paste: paste(name, ""),
+                base_revision: None,
+            };
+            act(&mut state, 0, request);
+        }
+        let move_folder = |from: &str, to: &str| ActionRequest::MoveFolder {
+            from: from.into(),
+            to: to.into(),
+        };
+
+        // a/x would replace b/x, so nothing moves
+        assert_eq!(
+            act(&mut state, 0, move_folder("a", "b")),
+            ActionResponse::Error(ActionError::AlreadyExists)
+        );
+        assert_eq!(names(&mut state, 0), ["a/a/x", "a/x", "b/x"]);
+
+        // a/a/x would replace a/x, which stays in place
+        assert_eq!(
+            act(&mut state, 0, move_folder("a/a", "a")),
+            ActionResponse::Error(ActionError::AlreadyExists)
+        );
+
+        // a/x may take the name of a/a/x, which moves on to a/a/a/x
+        assert_eq!(
+            act(&mut state, 0, move_folder("a", "a/a")),
+            ActionResponse::Done
+        );
+        assert_eq!(names(&mut state, 0), ["a/a/a/x", "a/a/x", "b/x"]);
+
+        assert_eq!(
+            act(&mut state, 0, move_folder("c", "d")),
+            ActionResponse::Error(ActionError::NotFound)
+        );
+        assert_eq!(
+            act(&mut state, 0, move_folder("", "d")),
+            ActionResponse::Error(ActionError::NotFound)
+        );
+    }
+
+    #[test]
+    fn grants_are_enforced() {
+        let mut state = state(2);
+        let get = || ActionRequest::Get {
+            name: "note".into(),
+        };
+        let grant = |permission| ActionRequest::Grant {
+            name: "note".into(),
+            grant: Grant {
+                grantee: state.session_and_rsa_keys[1].1.clone(),
+                permission,
+            },
+        };
+        let (read_grant, write_grant) = (grant(Permission::Read), grant(Permission::Write));
+        let append = ActionRequest::Append {
+            name: "note".into(),
+            text: "grantee".into(),
+        };
+        let request = ActionRequest::Mut {
+            paste: paste("note", "owner"),
+            base_revision: None,
+        };
+        act(&mut state, 0, request);
+        assert_eq!(
+            act(&mut state, 1, get()),
+            ActionResponse::Error(ActionError::NotFound)
+        );
+
+        // the owner's index paired with the grantee's encryption of the name
+        let key = state.fresh_session_key(1);
+        let forged = EncryptedName {
+            index: BlindIndex::new("note", &state.fresh_session_key(0)),
+            ..EncryptedName::encrypt("note", &key).unwrap()
+        };
+        for request in [
+            EncryptedActionRequest::Get {
+                name: forged.clone(),
+            },
+            EncryptedActionRequest::Remove { name: forged },
+        ] {
+            assert_eq!(
+                state.handle_action(&request, 1, &key).unwrap(),
+                ActionResponse::Error(ActionError::Forbidden)
+            );
+        }
+
+        assert_eq!(act(&mut state, 0, read_grant), ActionResponse::Done);
+        assert_eq!(
+            act(&mut state, 1, get()),
+            ActionResponse::Paste {
+                paste: paste("note", "owner"),
+                revision: 0,
+            }
+        );
+        for request in [
+            append.clone(),
+            ActionRequest::Mut {
+                paste: paste("note", "grantee"),
+                base_revision: Some(0),
+            },
+            ActionRequest::Remove {
+                name: "note".into(),
+            },
+        ] {
+            assert_eq!(
+                act(&mut state, 1, request),
+                ActionResponse::Error(ActionError::Forbidden)
+            );
+        }
+
+        assert_eq!(
+            act(&mut state, 0, write_grant.clone()),
+            ActionResponse::Done
+        );
+        assert_eq!(
+            act(&mut state, 1, append),
+            ActionResponse::Written { revision: 1 }
+        );
+        assert_eq!(
+            act(&mut state, 0, get()),
+            ActionResponse::Paste {
+                paste: paste("note", "owner\ngrantee"),
+                revision: 1,
+            }
+        );
+        // only the owner manages the paste itself
+        for request in [
+            write_grant,
+            ActionRequest::Rename {
+                from: "note".into(),
+                to: "other".into(),
+            },
+            ActionRequest::Remove {
+                name: "note".into(),
+            },
+        ] {
+            assert_eq!(
+                act(&mut state, 1, request),
+                ActionResponse::Error(ActionError::Forbidden)
+            );
+        }
+
+        let revoke = ActionRequest::Revoke {
+            name: "note".into(),
+            grantee: state.session_and_rsa_keys[1].1.clone(),
+        };
+        assert_eq!(act(&mut state, 0, revoke), ActionResponse::Done);
+        assert_eq!(
+            act(&mut state, 1, get()),
+            ActionResponse::Error(ActionError::NotFound)
+        );
+        assert_eq!(names(&mut state, 0), ["note"]);
+    }
+
+    #[test]
+    fn last_read_purges_paste() {
+        let mut state = state(1);
+        let request = ActionRequest::New {
+            paste: paste("note", "secret"),
+            expiry: Expiry {
+                expires_at: None,
+                max_reads: Some(4),
+            },
+        };
+        act(&mut state, 0, request);
+        let search = |query: &str| ActionRequest::Search {
+            query: query.into(),
+            options: ListOptions::default(),
+        };
+        let reads_left = |state: &mut State| {
+            let request = ActionRequest::List {
+                nonce: [0; 16],
+                options: ListOptions::default(),
+            };
+            let ActionResponse::List(paste_infos) = act(state, 0, request) else {
+                panic!("not a list");
+            };
+            paste_infos[0].reads_left
+        };
+
+        // searching shows no content, so it reads nothing
+        assert_eq!(
+            act(&mut state, 0, search("secret")),
+            ActionResponse::Matches(Vec::new())
+        );
+        let ActionResponse::Matches(search_matches) = act(&mut state, 0, search("note")) else {
+            panic!("not matches");
+        };
+        assert_eq!(search_matches.len(), 1);
+        assert!(!search_matches[0].snippet.contains("secret"));
+        assert_eq!(reads_left(&mut state), Some(4));
+
+        // downloading an attachment reads it once its last chunk is served
+        let attachment = Attachment {
+            name: "key.txt".into(),
+            mime: "text/plain".into(),
+            bytes: vec![0; ATTACHMENT_CHUNK_LEN + 1],
+        };
+        for chunk in attachment.chunks(1) {
+            let request = ActionRequest::Attach {
+                name: "note".into(),
+                chunk,
+            };
+            assert_eq!(act(&mut state, 0, request), ActionResponse::Done);
+        }
+        for (index, reads_left_after) in [(0, 4), (1, 3)] {
+            let request = ActionRequest::Download {
+                name: "note".into(),
+                attachment: "key.txt".into(),
+                index,
+            };
+            assert!(matches!(
+                act(&mut state, 0, request),
+                ActionResponse::Chunk(_)
+            ));
+            assert_eq!(reads_left(&mut state), Some(reads_left_after));
+        }
+
+        assert_eq!(
+            act(
+                &mut state,
+                0,
+                ActionRequest::Get {
+                    name: "note".into(),
+                },
+            ),
+            ActionResponse::Paste {
+                paste: paste("note", "secret"),
+                revision: 0,
+            }
+        );
+        assert_eq!(reads_left(&mut state), Some(2));
+        // a conflict shows the content as well
+        let request = ActionRequest::Mut {
+            paste: paste("note", "edit"),
+            base_revision: None,
+        };
+        assert!(matches!(
+            act(&mut state, 0, request),
+            ActionResponse::Conflict { .. }
+        ));
+        assert_eq!(reads_left(&mut state), Some(1));
+
+        let request = ActionRequest::Revision {
+            name: "note".into(),
+            revision: 0,
+        };
+        assert!(matches!(
+            act(&mut state, 0, request),
+            ActionResponse::Paste { .. }
+        ));
+        assert!(names(&mut state, 0).is_empty());
+        assert_eq!(
+            act(
+                &mut state,
+                0,
+                ActionRequest::Get {
+                    name: "note".into(),
+                },
+            ),
+            ActionResponse::Error(ActionError::NotFound)
+        );
+    }
+
+    #[test]
+    fn quota_applies_to_decrypted_paste() {
+        let mut state = state(1);
+        // each of them deflates to a few dozen bytes
+        let pastes = [
+            paste("note", &"a".repeat(MAX_CONTENT_LEN + 1)),
+            paste(&"a".repeat(MAX_NAME_LEN + 1), ""),
+            Paste {
+                name: "note".into(),
+                content: String::new(),
+                tags: vec!["a".repeat(MAX_TAGS_LEN + 1)],
+            },
+        ];
+        for paste in pastes {
+            let request = ActionRequest::Mut {
+                paste,
+                base_revision: None,
+            };
+            assert_eq!(
+                act(&mut state, 0, request),
+                ActionResponse::Error(ActionError::QuotaExceeded)
+            );
+        }
+        assert!(names(&mut state, 0).is_empty());
+    }
+
+    #[test]
+    fn history_is_bounded_and_restorable() {
+        let mut state = state(1);
+        let last = MAX_REVISIONS as u64 + 1;
+        for revision in 0..=last {
+            let request = ActionRequest::Mut {
+                paste: paste("note", &format!("revision {revision}")),
+                base_revision: revision.checked_sub(1),
+            };
+            assert_eq!(
+                act(&mut state, 0, request),
+                ActionResponse::Written { revision }
+            );
+        }
+        let history = |state: &mut State| {
+            let request = ActionRequest::History {
+                name: "note".into(),
+            };
+            let ActionResponse::History(revision_infos) = act(state, 0, request) else {
+                panic!("not a history");
+            };
+            revision_infos
+                .iter()
+                .map(|info| info.revision)
+                .collect::<Vec<_>>()
+        };
+        // newest first, the oldest revision was evicted
+        assert_eq!(history(&mut state), (1..=last).rev().collect::<Vec<_>>());
+        let revision = |revision| ActionRequest::Revision {
+            name: "note".into(),
+            revision,
+        };
+        assert_eq!(
+            act(&mut state, 0, revision(0)),
+            ActionResponse::Error(ActionError::NotFound)
+        );
+        assert_eq!(
+            act(&mut state, 0, revision(1)),
+            ActionResponse::Paste {
+                paste: paste("note", "revision 1"),
+                revision: 1,
+            }
+        );
+        let restore = |revision| ActionRequest::Restore {
+            name: "note".into(),
+            revision,
+        };
+        assert_eq!(
+            act(&mut state, 0, restore(0)),
+            ActionResponse::Error(ActionError::NotFound)
+        );
+
+        // a restore is a new revision, which evicts the next oldest one
+        assert_eq!(
+            act(&mut state, 0, restore(1)),
+            ActionResponse::Written { revision: last + 1 }
+        );
+        assert_eq!(
+            act(
+                &mut state,
+                0,
+                ActionRequest::Get {
+                    name: "note".into(),
+                },
+            ),
+            ActionResponse::Paste {
+                paste: paste("note", "revision 1"),
+                revision: last + 1,
+            }
+        );
+        assert_eq!(
+            history(&mut state),
+            (2..=last + 1).rev().collect::<Vec<_>>()
+        );
     }
 }
@@ -0,0 +1,58 @@
+use msg::Paste;
+
+const SNIPPET_RADIUS: usize = 32;
+
+// Case insensitive, compared char by char so that indices into the lowered
+// text are also indices into the original one.
+fn lower_chars(text: &str) -> Vec<char> {
+    text.chars()
+        .map(|ch| ch.to_lowercase().next().unwrap_or(ch))
+        .collect()
+}
+
+fn find(haystack: &[char], needle: &[char]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn snippet(content: &[char], start: usize, end: usize) -> String {
+    let from = start.saturating_sub(SNIPPET_RADIUS);
+    let to = (end + SNIPPET_RADIUS).min(content.len());
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(
+        content[from..to]
+            .iter()
+            .map(|&ch| if ch.is_whitespace() { ' ' } else { ch }),
+    );
+    if to < content.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+// Returns a snippet around the first keyword found in the content.
+pub fn search(paste: &Paste, query: &str) -> Option<String> {
+    let name = lower_chars(&paste.name);
+    let content: Vec<_> = paste.content.chars().collect();
+    let lowered_content = lower_chars(&paste.content);
+    let mut found_in_content = None;
+    for keyword in query.split_whitespace().map(lower_chars) {
+        match find(&lowered_content, &keyword) {
+            Some(start) => {
+                found_in_content.get_or_insert((start, start + keyword.len()));
+            }
+            None => {
+                find(&name, &keyword)?;
+            }
+        }
+    }
+    let (start, end) = found_in_content.unwrap_or((0, 0));
+    Some(snippet(&content, start, end))
+}